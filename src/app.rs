@@ -1,50 +1,132 @@
 use eframe::egui::{self, DroppedFile, ScrollArea, Vec2};
-use std::collections::{HashMap, HashSet};
+use std::{collections::HashMap, path::PathBuf};
 
 use structopt::StructOpt;
 
+use crate::dsp::{ChainSlot, ChannelSettings};
+use crate::equalizer::EqualizerSettings;
+use crate::loudness::{GainMode, Loudness, LoudnessScanner};
+use crate::output::{output_devices, Retry};
+use crate::player::{Command, Event, Player};
+use crate::stream::{FadePolicy, StreamManager, StreamState};
 use crate::theme::Theme;
 use crate::ui_components::*;
-use kira::instance::{InstanceSettings, InstanceState, StopInstanceSettings};
 use kira::manager::AudioManagerSettings;
-use kira::{
-    instance::{PauseInstanceSettings, ResumeInstanceSettings},
-    manager::AudioManager,
-};
-use log::{debug, info};
+use log::{error, info};
 
 use super::sound::*;
 use eframe::epi;
 
 #[cfg(feature = "persistence")]
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
 
 #[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[serde(default)]
 pub struct ApplicationState {
     #[serde(skip)]
-    pub audiomanager: Option<AudioManager>,
-    pub active_sound: Option<MetaSound>,
+    pub audiomanager: Option<StreamManager>,
+    pub player: Player,
     volume: f64,
-    queue: SoundQueue,
-    play_count: HashMap<MetaSound, usize>,
-    favourites: HashSet<MetaSound>,
-    bookmarks: HashSet<MetaSound>,
     theme: Theme,
     powersave: bool,
+    /// Time stretch sped up sounds, instead of resampling them
+    preserve_pitch: bool,
+    /// Fades for pause, resume, stop and seek
+    fades: FadePolicy,
+    replaygain: GainMode,
+    /// Measured loudness of files without ReplayGain tags
+    loudness: HashMap<PathBuf, Loudness>,
+    #[serde(skip)]
+    scanner: Option<LoudnessScanner>,
+    equalizer: EqualizerSettings,
+    /// Effects after the equalizer, in order
+    effects: Vec<ChainSlot>,
+    /// Compress and limit the output, for quiet listening
+    night_mode: bool,
+    channels: ChannelSettings,
+    /// Output device, `None` for the system default
+    device: Option<String>,
+    /// Output devices to choose from
+    #[serde(skip)]
+    devices: Vec<String>,
+    /// Paces attempts to create the audio manager, while there is none
+    #[serde(skip)]
+    manager_retry: Retry,
 }
 
 impl Default for ApplicationState {
     fn default() -> Self {
         Self {
             audiomanager: None,
-            active_sound: None,
+            player: Player::default(),
             volume: 1.0,
-            queue: vec![],
-            play_count: HashMap::default(),
-            favourites: HashSet::default(),
-            bookmarks: HashSet::default(),
             theme: Theme::default(),
             powersave: true,
+            preserve_pitch: true,
+            fades: FadePolicy::default(),
+            replaygain: GainMode::default(),
+            loudness: HashMap::default(),
+            scanner: None,
+            equalizer: EqualizerSettings::default(),
+            effects: vec![],
+            night_mode: false,
+            channels: ChannelSettings::default(),
+            device: None,
+            devices: vec![],
+            manager_retry: Retry::default(),
+        }
+    }
+}
+
+/// Only tells whether a saved state has a `player`, as saves from before the player
+/// was split out keep its state at the top level
+#[cfg(feature = "persistence")]
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SavedShape {
+    #[serde(deserialize_with = "present")]
+    player: bool,
+}
+
+#[cfg(feature = "persistence")]
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    IgnoredAny::deserialize(deserializer).map(|_| true)
+}
+
+impl ApplicationState {
+    /// The saved state, or the default one if there is none
+    #[cfg(feature = "persistence")]
+    fn load(storage: &dyn epi::Storage) -> Self {
+        let mut state: ApplicationState = epi::get_value(storage, epi::APP_KEY).unwrap_or_default();
+        let shape: Option<SavedShape> = epi::get_value(storage, epi::APP_KEY);
+        if shape.is_some_and(|s| !s.player) {
+            if let Some(player) = epi::get_value(storage, epi::APP_KEY) {
+                state.player = player;
+            }
+        }
+        state
+    }
+
+    /// Create the audio manager and apply the saved settings to it
+    fn start_audio(&mut self) {
+        self.manager_retry.attempted();
+        self.audiomanager =
+            match StreamManager::new(AudioManagerSettings::default(), self.device.as_deref()) {
+                Ok(manager) => Some(manager),
+                Err(e) => {
+                    error!("Can't start audio: {}", e);
+                    None
+                }
+            };
+        if let Some(manager) = &mut self.audiomanager {
+            manager.set_preserve_pitch(self.preserve_pitch);
+            manager.set_fades(self.fades);
+            manager.set_equalizer(self.equalizer.effective());
+            manager.set_effects(&self.effects);
+            manager.set_volume(self.volume);
+            manager.set_night_mode(self.night_mode);
+            manager.set_channels(self.channels);
+            manager.set_gain_mode(self.replaygain.resolve(self.player.shuffled()));
         }
     }
 }
@@ -62,9 +144,7 @@ impl epi::App for ApplicationState {
         storage: Option<&dyn epi::Storage>,
     ) {
         if let Some(storage) = storage {
-            let storage: ApplicationState =
-                epi::get_value(storage, epi::APP_KEY).unwrap_or_default();
-            *self = storage;
+            *self = Self::load(storage);
         }
 
         
@@ -74,46 +154,49 @@ impl epi::App for ApplicationState {
         let args = super::Opt::from_args();
 
         // Create an AudioManager
-        self.audiomanager = AudioManager::new(AudioManagerSettings::default()).ok();
+        self.start_audio();
+        self.devices = output_devices();
 
         // If the application was called with files as an argument, play the first
         if let Some(first_arg) = args.files.first() {
             if let Some(manager) = &mut self.audiomanager {
-                // restore previous volume
-                let _ = manager.main_track().set_volume(self.volume);
-                // load the sound from disk
-                let sound = MetaSound::default()
-                    .with_path(first_arg)
-                    .try_meta()
-                    .load_soundhandle(manager);
-
-                self.active_sound = Some(sound.clone());
-                self.active_sound.as_mut().map(|s| s.play());
-
-                // check if playlist has this sound
-                if !self.queue.contains(&sound) {
-                    self.queue.push(sound);
-                }
+                let sound = MetaSound::default().with_path(first_arg).try_meta();
+                self.player
+                    .command(Command::Enqueue(sound.clone()), manager);
+                self.player.command(Command::PlaySound(sound), manager);
             }
         }
     }
 
     #[cfg(feature = "persistence")]
     fn save(&mut self, storage: &mut dyn epi::Storage) {
+        self.player.remember_position();
         epi::set_value(storage, epi::APP_KEY, self);
     }
 
     fn update(&mut self, ctx: &egui::CtxRef, _frame: &epi::Frame) {
+        if self.audiomanager.is_none() && self.manager_retry.due() {
+            self.start_audio();
+        }
+
         let ApplicationState {
             audiomanager: manager,
-            active_sound,
+            player,
             volume,
-            queue,
-            bookmarks,
-            favourites,
-            play_count,
             theme,
             powersave,
+            preserve_pitch,
+            fades,
+            replaygain,
+            loudness,
+            scanner,
+            equalizer,
+            effects,
+            night_mode,
+            channels,
+            device,
+            devices,
+            manager_retry,
         } = self;
         if egui::CentralPanel::default()
             .show(ctx, |ui| {
@@ -121,188 +204,290 @@ impl epi::App for ApplicationState {
                 ctx.request_repaint();
 
                 // info!("{:?}", ctx.input().raw);
+                let mut dropped = vec![];
                 if !ctx.input().raw.dropped_files.is_empty() {
                     info!("{:?}", ctx.input().raw.dropped_files);
-                    handle_dropped(&ctx.input().raw.dropped_files, queue);
+                    handle_dropped(&ctx.input().raw.dropped_files, &mut dropped);
                 }
 
                 if let Some(manager) = manager {
-                    if let Some(current_metasound) = active_sound {
-                        ui.label(&current_metasound.name);
-                        if ui
-                            .add(
-                                egui::Slider::new(volume, 0.0..=3.0)
-                                    .text("🔈")
-                                    .show_value(false),
-                            )
-                            .changed()
-                        {
-                            let _ = manager.main_track().set_volume(*volume as f64);
-                        }
+                    manager.keep_alive();
+                    output_error_ui(manager, ui);
+                    for sound in dropped {
+                        player.command(Command::Enqueue(sound), manager);
                     }
+                    let mut commands = vec![];
+                    if let Some(current_metasound) = player.active_sound() {
+                        ui.horizontal(|ui| {
+                            ui.label(&current_metasound.name);
+                            if current_metasound.channels == 1 {
+                                ui.label("mono")
+                                    .on_hover_text("This sound has a single channel");
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            if ui
+                                .add(
+                                    egui::Slider::new(volume, 0.0..=3.0)
+                                        .text("🔈")
+                                        .show_value(false),
+                                )
+                                .changed()
+                            {
+                                manager.set_volume(*volume);
+                            }
 
-                    if let Some(current_metasound) = active_sound {
-                        if let Some(instancehandle) = current_metasound.instancehandle.as_mut() {
-                            if let Some(soundhandle) = current_metasound.soundhandle.as_mut() {
-                                let cur_pos = instancehandle.position();
-                                let len = soundhandle.duration();
-                                let progress = (cur_pos / len) as f32;
-
-                                // current_metasound.soundhandle.unwrap().
-                                let response = scrubber(ui, progress);
-                                if ui.input().pointer.any_pressed() {
-                                    if let Some(pos) = response.interact_pointer_pos() {
-                                        let w = ui.available_size().x;
-                                        let p = pos.x;
-                                        let fac = (p / w) as f64;
-                                        let _ = instancehandle.seek_to(fac * len);
-                                    }
+                            if ui
+                                .selectable_label(*night_mode, "🌙")
+                                .on_hover_text("Night mode: even out quiet and loud parts")
+                                .clicked()
+                            {
+                                *night_mode = !*night_mode;
+                            }
+
+                            let mut speed = current_metasound.speed;
+                            if ui
+                                .add(
+                                    egui::Slider::new(&mut speed, 0.5..=3.0)
+                                        .text("⏩")
+                                        .suffix("×"),
+                                )
+                                .changed()
+                            {
+                                commands.push(Command::SetSpeed(speed));
+                            }
+
+                            if ui
+                                .selectable_label(current_metasound.skip_silence, "✂")
+                                .on_hover_text("Skip silence in this sound")
+                                .clicked()
+                            {
+                                commands.push(Command::SetSkipSilence(
+                                    !current_metasound.skip_silence,
+                                ));
+                            }
+                            if current_metasound.skip_silence {
+                                let saved = current_metasound
+                                    .streamhandle
+                                    .as_ref()
+                                    .map(|h| h.silence_skipped())
+                                    .unwrap_or_default()
+                                    as u64;
+                                ui.label(format!("{}:{:02} saved", saved / 60, saved % 60))
+                                    .on_hover_text("Silence skipped so far");
+                            }
+                        });
+                    }
+
+                    if let Some(current_metasound) = player.active_sound() {
+                        if let Some(streamhandle) = current_metasound.streamhandle.as_ref() {
+                            let cur_pos = streamhandle.position();
+                            let len = streamhandle.duration();
+                            // some containers don't tell their length up front
+                            let progress = if len > 0.0 {
+                                (cur_pos / len) as f32
+                            } else {
+                                0.0
+                            };
+
+                            let looped = current_metasound.looped;
+                            let (loop_start, loop_end) =
+                                (current_metasound.loop_start, current_metasound.loop_end);
+                            let fraction = |t: f64| {
+                                if len > 0.0 {
+                                    Some((t / len) as f32)
+                                } else {
+                                    None
+                                }
+                            };
+                            let loop_marks = (
+                                fraction(loop_start).filter(|_| looped || loop_start > 0.0),
+                                fraction(loop_end).filter(|_| looped),
+                            );
+
+                            let response = scrubber(ui, progress, loop_marks);
+                            if ui.input().pointer.any_pressed() {
+                                if let Some(pos) = response.interact_pointer_pos() {
+                                    let w = ui.available_size().x;
+                                    let p = pos.x;
+                                    let fac = (p / w) as f64;
+                                    commands.push(Command::Seek(fac * len));
                                 }
                             }
                         }
                     } else {
                         ui.label("No sound active");
                     }
+                    for command in commands {
+                        player.command(command, manager);
+                    }
 
-                    ui.horizontal(|ui| {
-                        if let Some(current_metasound) = active_sound {
-                            if ui.add(egui::Button::new("⏮")).clicked() {
-                                if let Some(i) = queue.to_index(&current_metasound.clone()) {
-                                    let ri = (i as isize - 1).max(0) as usize;
-                                    play_as_active(active_sound, &queue[ri], manager, play_count);
-                                }
-                            }
+                    player.update(manager);
+                    for event in player.take_events() {
+                        match event {
+                            Event::TrackChanged(s) => info!("Playing {}", s.name),
+                            Event::Finished(s) => info!("Finished {}", s.name),
+                            Event::EndOfQueue => info!("End of the playlist"),
+                            Event::PositionChanged(_) => {}
                         }
+                    }
 
-                        // info about current song
-                        if let Some(current_metasound) = active_sound {
-                            if let Some(instancehandle) = current_metasound.instancehandle.as_mut()
-                            {
-                                if let Some(soundhandle) = current_metasound.soundhandle.as_mut() {
-                                    // done playing?
-                                    debug!(
-                                        "{}",
-                                        instancehandle.position() - soundhandle.duration()
-                                    );
-                                    if instancehandle.position() - soundhandle.duration() > -0.05 {
-                                        info!("Sound has finished playing, next one!");
-                                        if let Some(i) = queue.to_index(current_metasound) {
-                                            let ri = (i + 1).min(queue.len() - 1);
-                                            play_as_active(
-                                                active_sound,
-                                                &queue[ri],
-                                                manager,
-                                                play_count,
-                                            );
-                                        }
-                                    }
-                                }
-                            }
+                    ui.horizontal(|ui| {
+                        if player.active_sound().is_some()
+                            && ui
+                                .add(egui::Button::new("⏮"))
+                                .on_hover_text("Start over, or go back within the first seconds")
+                                .clicked()
+                        {
+                            player.command(Command::Prev, manager);
                         }
 
-                        // info about current song
-                        if let Some(current_metasound) = active_sound {
-                            if let Some(instancehandle) = current_metasound.instancehandle.as_mut()
-                            {
-                                if let Some(soundhandle) = current_metasound.soundhandle.as_mut() {
-                                    match instancehandle.state() {
-                                        InstanceState::Playing => {
-                                            if ui.button("⏸").clicked() {
-                                                let _ =
-                                                    soundhandle.pause(PauseInstanceSettings::new());
-                                            }
-                                            if ui.button("⏹").clicked() {
-                                                let _ =
-                                                    soundhandle.stop(StopInstanceSettings::new());
-                                            }
-                                        }
-                                        InstanceState::Paused(_) => {
-                                            if ui.button("▶").clicked() {
-                                                let _ = soundhandle
-                                                    .resume(ResumeInstanceSettings::new());
-                                            }
-                                            if ui.button("⏹").clicked() {
-                                                let _ =
-                                                    soundhandle.stop(StopInstanceSettings::new());
-                                            }
-                                        }
-                                        InstanceState::Stopped => {
-                                            if ui.button("▶").clicked() {
-                                                let _ = soundhandle.play(InstanceSettings::new());
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                } else {
-                                    ui.label("No active sound handle");
+                        let state = player
+                            .active_sound()
+                            .map(|s| s.streamhandle.as_ref().map(|h| h.state()));
+                        match state {
+                            Some(Some(StreamState::Playing)) => {
+                                if ui.button("⏸").clicked() {
+                                    player.command(Command::Pause, manager);
                                 }
-                            } else {
-                                // There is no active instance handle, offer to play
-                                // ui.label("No active sound instance");
+                                if ui.button("⏹").clicked() {
+                                    player.command(Command::Stop, manager);
+                                }
+                            }
+                            Some(Some(StreamState::Paused)) => {
                                 if ui.button("▶").clicked() {
-                                    *current_metasound =
-                                        current_metasound.load_soundhandle(manager);
-                                    info!("{:?}", current_metasound.play());
-                                    *play_count.entry(current_metasound.clone()).or_insert(0) += 1;
+                                    player.command(Command::Play, manager);
+                                }
+                                if ui.button("⏹").clicked() {
+                                    player.command(Command::Stop, manager);
                                 }
                             }
-
-                            if ui
-                                .add(
-                                    egui::Button::new("⏭"), // .enabled(*queue_index < queue.len())
-                                )
-                                .clicked()
-                            {
-                                if let Some(i) = queue.to_index(current_metasound) {
-                                    let ri = (i + 1).min(queue.len() - 1);
-                                    play_as_active(active_sound, &queue[ri], manager, play_count);
+                            // stopped, finished or not loaded yet
+                            Some(_) => {
+                                if ui.button("▶").clicked() {
+                                    player.command(Command::Play, manager);
                                 }
                             }
-                        } else {
-                            ui.label("No sound active");
+                            None => {
+                                ui.label("No sound active");
+                            }
                         }
 
-                        if let Some(s) = active_sound {
-                            if ui.button("♡").clicked() {
-                                favourites.insert(s.clone());
-                            }
+                        if player.active_sound().is_some() && ui.add(egui::Button::new("⏭")).clicked()
+                        {
+                            player.command(Command::Next, manager);
                         }
 
-                        if let Some(s) = active_sound {
-                            if ui.button("🔖").clicked() {
-                                if let Some(instancehandle) = &s.instancehandle {
-                                    s.bookmarks.push(instancehandle.position());
-                                    let mut prev_bookmarks = bookmarks
-                                        .get(s)
-                                        .map(|b| b.bookmarks.clone())
-                                        .unwrap_or_default();
-                                    prev_bookmarks.extend(s.bookmarks.clone());
-                                    prev_bookmarks.sort_by(|a, b| a.partial_cmp(b).unwrap());
-                                    prev_bookmarks.dedup();
-                                    debug!("{:?}", prev_bookmarks);
-                                    s.bookmarks = prev_bookmarks;
-                                    bookmarks.replace(s.clone());
-                                }
+                        let repeat = player.repeat();
+                        if ui
+                            .selectable_label(repeat != RepeatMode::Off, repeat.icon())
+                            .on_hover_text(format!("Repeat: {:?}", repeat))
+                            .clicked()
+                        {
+                            player.command(Command::SetRepeat(repeat.cycle()), manager);
+                        }
+
+                        if ui
+                            .selectable_label(player.shuffled(), "🔀")
+                            .on_hover_text("Shuffle")
+                            .clicked()
+                        {
+                            player.command(Command::SetShuffle(!player.shuffled()), manager);
+                        }
+
+                        sleep_ui(player, manager, ui);
+
+                        if player.active_sound().is_some() && ui.button("♡").clicked() {
+                            player.command(Command::Favourite, manager);
+                        }
+
+                        if player.active_sound().is_some() && ui.button("🔖").clicked() {
+                            player.command(Command::Bookmark, manager);
+                        }
+
+                        if let Some(s) = player.active_sound() {
+                            let loaded = s.streamhandle.is_some();
+                            let has_loop = s.looped || s.loop_start > 0.0;
+                            if loaded && ui.button("A").on_hover_text("Loop from here").clicked() {
+                                player.command(Command::MarkLoopStart, manager);
+                            }
+                            if loaded && ui.button("B").on_hover_text("Loop until here").clicked() {
+                                player.command(Command::MarkLoopEnd, manager);
+                            }
+                            if has_loop && ui.button("🗙").on_hover_text("Clear loop").clicked() {
+                                player.command(Command::ClearLoop, manager);
                             }
                         }
 
                         // end horizontal layout
                     });
 
+                    scan_progress_ui(scanner, ui);
+
                     ScrollArea::new([false,true]).show(ui, |ui| {
-                        playlist_ui(queue, active_sound, play_count, manager, ui);
-                        playcount_ui(active_sound, play_count, manager, ui);
-                        favourite_ui(active_sound, favourites, play_count, manager, ui);
-                        bookmark_ui(active_sound, bookmarks, manager, ui);
-                        settings_ui(theme, powersave, ui);
+                        playlist_ui(player, manager, ui);
+                        playcount_ui(player, manager, ui);
+                        favourite_ui(player, manager, ui);
+                        bookmark_ui(player, manager, ui);
+                        let settings = Settings {
+                            theme,
+                            powersave,
+                            preserve_pitch,
+                            fades,
+                            replaygain,
+                            device,
+                            devices,
+                        };
+                        settings_ui(settings, player, manager, ui);
+                        equalizer_ui(equalizer, ui);
+                        effects_ui(effects, ui);
+                        channels_ui(channels, ui);
                     });
+
+                    let gain_mode = replaygain.resolve(player.shuffled());
+                    if manager.gain_mode() != gain_mode {
+                        player.command(Command::SetGainMode(gain_mode), manager);
+                    }
+                    normalize_untagged(player, manager, scanner, loudness);
+                    if manager.equalizer() != equalizer.effective() {
+                        manager.set_equalizer(equalizer.effective());
+                    }
+                    if manager.effects() != effects.as_slice() {
+                        manager.set_effects(effects);
+                    }
+                    if manager.device() != device.as_deref() {
+                        if let Err(e) = manager.set_device(device.as_deref()) {
+                            error!("Can't switch the output device: {}", e);
+                        }
+                    }
+                    if manager.channels() != *channels {
+                        manager.set_channels(*channels);
+                    }
+                    if manager.night_mode() != *night_mode {
+                        manager.set_night_mode(*night_mode);
+                    }
+                    if manager.fades() != *fades {
+                        manager.set_fades(*fades);
+                    }
+                    if manager.preserve_pitch() != *preserve_pitch {
+                        player.command(Command::SetPreservePitch(*preserve_pitch), manager);
+                    }
                 } else {
-                    ui.label("No Audio manager");
+                    ui.horizontal(|ui| {
+                        ui.label("No Audio manager");
+                        if ui.button("Retry").clicked() {
+                            *manager_retry = Retry::default();
+                        }
+                    });
                 }
             })
             .response
             .hovered()
             || !*powersave
+            || player.sleep_timer().is_some()
+            || scanner.as_ref().and_then(|s| s.progress()).is_some()
+            // keep retrying while there is no sound
+            || manager.as_ref().map_or(true, |m| m.output_error().is_some())
         {
             // only repaint on hover
             ctx.request_repaint();
@@ -327,6 +512,40 @@ impl epi::App for ApplicationState {
     }
 }
 
+/// Measure the loudness of sounds without ReplayGain tags in the background,
+/// and use it as their track gain once it is known.
+fn normalize_untagged(
+    player: &mut Player,
+    manager: &mut StreamManager,
+    scanner: &mut Option<LoudnessScanner>,
+    loudness: &mut HashMap<PathBuf, Loudness>,
+) {
+    if manager.gain_mode() == GainMode::Off {
+        return;
+    }
+    if scanner.is_none() {
+        *scanner = LoudnessScanner::new().ok();
+    }
+    let scanner = match scanner {
+        Some(scanner) => scanner,
+        None => return,
+    };
+    loudness.extend(scanner.finished());
+
+    let mut measured = vec![];
+    for sound in player.sounds().filter(|s| s.replaygain.is_empty()) {
+        match loudness.get(&sound.path) {
+            // silent files measure no gain, and stay untagged
+            Some(loudness) if loudness.replaygain() == sound.replaygain => {}
+            Some(loudness) => measured.push((sound.path.clone(), loudness.replaygain())),
+            None => scanner.scan(&sound.path),
+        }
+    }
+    for (path, gain) in measured {
+        player.command(Command::SetReplayGain(path, gain), manager);
+    }
+}
+
 /// Recurse dropped folders
 fn handle_dropped(dropped_files: &Vec<DroppedFile>, queue: &mut SoundQueue) {
     for p in dropped_files.iter().filter_map(|d| d.path.as_ref()) {
@@ -334,7 +553,7 @@ fn handle_dropped(dropped_files: &Vec<DroppedFile>, queue: &mut SoundQueue) {
             for f in walkdir::WalkDir::new(p)
                 .into_iter()
                 .filter_map(|e| e.ok())
-                .filter(|e| e.path().extension().is_some())
+                .filter(|e| e.file_type().is_file() && e.path().extension().is_some())
             {
                 let s = MetaSound::default().with_path(f.path()).try_meta();
                 if s.is_supported() {
@@ -349,3 +568,124 @@ fn handle_dropped(dropped_files: &Vec<DroppedFile>, queue: &mut SoundQueue) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tone_wav;
+    use std::fs;
+
+    fn dropped(path: &std::path::Path) -> DroppedFile {
+        DroppedFile {
+            path: Some(path.to_path_buf()),
+            ..DroppedFile::default()
+        }
+    }
+
+    /// Keeps saved values in memory
+    #[derive(Default)]
+    struct Memory(HashMap<String, String>);
+
+    impl epi::Storage for Memory {
+        fn get_string(&self, key: &str) -> Option<String> {
+            self.0.get(key).cloned()
+        }
+
+        fn set_string(&mut self, key: &str, value: String) {
+            self.0.insert(key.to_string(), value);
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    fn saved(ron: &str) -> Memory {
+        let mut storage = Memory::default();
+        epi::Storage::set_string(&mut storage, epi::APP_KEY, ron.to_string());
+        storage
+    }
+
+    /// A value as it is saved, to compare values that can't be compared otherwise
+    fn ron<T: Serialize>(value: &T) -> String {
+        let mut storage = Memory::default();
+        epi::set_value(&mut storage, epi::APP_KEY, value);
+        storage.0.remove(epi::APP_KEY).unwrap()
+    }
+
+    fn player(ron: &str) -> Player {
+        epi::get_value(&saved(ron), epi::APP_KEY).unwrap()
+    }
+
+    #[test]
+    fn player_settings_survive_an_empty_playlist() {
+        let state = ApplicationState {
+            player: player("(crossfade: 4.0, repeat: All)"),
+            ..ApplicationState::default()
+        };
+        let mut storage = Memory::default();
+        epi::set_value(&mut storage, epi::APP_KEY, &state);
+        let loaded = ApplicationState::load(&storage);
+        assert_eq!(ron(&loaded.player), ron(&state.player));
+        assert_ne!(ron(&loaded.player), ron(&Player::default()));
+    }
+
+    #[test]
+    fn player_settings_are_taken_from_old_saves() {
+        let loaded = ApplicationState::load(&saved("(crossfade: 4.0, volume: 0.5)"));
+        assert_eq!(ron(&loaded.player), ron(&player("(crossfade: 4.0)")));
+        assert_eq!(loaded.volume, 0.5);
+    }
+
+    #[test]
+    fn shuffle_orders_are_taken_from_old_saves() {
+        let queue = vec![
+            MetaSound::default().with_path("/music/b.mp3"),
+            MetaSound::default().with_path("/music/a.mp3"),
+        ];
+        // the order used to keep whole sounds
+        let old = format!("(queue: {0}, shuffle: Some((order: {0})))", ron(&queue));
+        let new = format!(
+            r#"(queue: {}, shuffle: Some((order: ["/music/b.mp3", "/music/a.mp3"])))"#,
+            ron(&queue)
+        );
+        assert!(player(&old).shuffled());
+        assert_eq!(ron(&player(&old)), ron(&player(&new)));
+    }
+
+    fn names(queue: &SoundQueue) -> Vec<&str> {
+        let mut names = queue.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn dropped_folders_are_searched() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = dir.path().join("album");
+        let disc = album.join("disc_2");
+        fs::create_dir_all(&disc).unwrap();
+        tone_wav(&album, "one.wav", 0.1);
+        tone_wav(&disc, "two.wav", 0.1);
+        fs::write(album.join("cover.jpg"), b"").unwrap();
+        fs::write(disc.join("README"), b"").unwrap();
+        // a folder that looks like a sound
+        fs::create_dir(album.join("extra.wav")).unwrap();
+
+        let mut queue = vec![];
+        handle_dropped(&vec![dropped(&album)], &mut queue);
+        assert_eq!(names(&queue), vec!["one.wav", "two.wav"]);
+    }
+
+    #[test]
+    fn dropped_files_are_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let sound = tone_wav(dir.path(), "my_song.wav", 0.1);
+        let text = dir.path().join("notes.txt");
+        fs::write(&text, b"").unwrap();
+
+        let mut queue = vec![];
+        let files = vec![dropped(&sound), dropped(&text), DroppedFile::default()];
+        handle_dropped(&files, &mut queue);
+        assert_eq!(names(&queue), vec!["my song.wav"]);
+        assert_eq!(queue[0].path, sound);
+    }
+}
@@ -0,0 +1,54 @@
+//! Second order IIR filters, the building block of the equalizer and the loudness meter
+
+use std::f64::consts::PI;
+
+/// A second order IIR filter, in direct form I so coefficients can change while it runs
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// From normalized coefficients, `a[0]` is expected to be 1
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    /// Boost or cut by `gain` dB around `freq`, from the Audio EQ Cookbook
+    pub fn peaking(sample_rate: f64, freq: f64, q: f64, gain: f64) -> Self {
+        let mut filter = Self::new([1.0, 0.0, 0.0], [1.0, 0.0, 0.0]);
+        filter.set_peaking(sample_rate, freq, q, gain);
+        filter
+    }
+
+    /// Change to a peaking filter, keeping the state so there is no click
+    pub fn set_peaking(&mut self, sample_rate: f64, freq: f64, q: f64, gain: f64) {
+        let a = 10f64.powf(gain / 40.0);
+        let w = 2.0 * PI * freq.min(sample_rate * 0.49) / sample_rate;
+        let alpha = w.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha / a;
+        self.b = [
+            (1.0 + alpha * a) / a0,
+            -2.0 * w.cos() / a0,
+            (1.0 - alpha * a) / a0,
+        ];
+        self.a = [1.0, -2.0 * w.cos() / a0, (1.0 - alpha / a) / a0];
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
@@ -0,0 +1,728 @@
+//! An ordered chain of effects on the main track, configured from the UI,
+//! and the output stage after it
+
+use anyhow::{anyhow, Error};
+use kira::{mixer::effect::Effect, parameter::Parameters, Frame};
+#[cfg(feature = "persistence")]
+use serde::{Deserialize, Serialize};
+use std::{
+    any::Any,
+    convert::TryFrom,
+    fmt::Debug,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+};
+
+/// An effect in the chain. To add one, implement this and add it to `EFFECTS`.
+pub trait Dsp: Any + Debug + Send + DspClone + AsAny {
+    /// Shown in the chain, and what the effect is saved as
+    fn name(&self) -> &'static str;
+
+    /// The settings, to show and save
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![]
+    }
+
+    /// Called before the first frame, and whenever the effect was replaced
+    fn init(&mut self, _sample_rate: f64) {}
+
+    fn process(&mut self, frame: Frame) -> Frame;
+
+    /// Take over the running state of the effect this one replaces, so changing
+    /// a setting does not reset it. `previous` may be any effect.
+    fn carry_state(&mut self, _previous: &dyn Dsp) {}
+}
+
+/// Copies effects behind a `Box`, for any effect that is `Clone`
+pub trait DspClone {
+    fn clone_box(&self) -> Box<dyn Dsp>;
+}
+
+impl<T: Dsp + Clone> DspClone for T {
+    fn clone_box(&self) -> Box<dyn Dsp> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Dsp> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Gives access to the concrete type of an effect
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A setting of an effect
+pub struct Parameter<'a> {
+    pub name: &'static str,
+    pub value: &'a mut f64,
+    pub range: RangeInclusive<f64>,
+}
+
+impl<'a> Parameter<'a> {
+    pub fn new(name: &'static str, value: &'a mut f64, range: RangeInclusive<f64>) -> Self {
+        Self { name, value, range }
+    }
+}
+
+/// Every effect the chain offers, with default settings
+const EFFECTS: &[fn() -> Box<dyn Dsp>] = &[
+    || Box::new(Compressor::default()),
+    || Box::new(Limiter::default()),
+    || Box::new(Widener::default()),
+    || Box::new(Downmix),
+    || Box::new(Balance::default()),
+];
+
+/// One of each effect, to pick from
+pub fn available() -> Vec<Box<dyn Dsp>> {
+    EFFECTS.iter().map(|create| create()).collect()
+}
+
+/// A new effect by its name
+fn create(name: &str) -> Option<Box<dyn Dsp>> {
+    available().into_iter().find(|e| e.name() == name)
+}
+
+/// `previous` as the concrete effect `T`, if it is one
+fn downcast<T: Dsp>(previous: &dyn Dsp) -> Option<&T> {
+    previous.as_any().downcast_ref()
+}
+
+/// Names and values of the settings of an effect
+fn values(effect: &dyn Dsp) -> Vec<(&'static str, f64)> {
+    effect
+        .clone_box()
+        .parameters()
+        .into_iter()
+        .map(|p| (p.name, *p.value))
+        .collect()
+}
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.max(1e-9).log10()
+}
+
+/// Coefficient of a one pole smoother reaching about 63% within `ms`
+fn smoothing(ms: f64, sample_rate: f64) -> f64 {
+    (-1.0 / (ms.max(0.01) / 1000.0 * sample_rate)).exp()
+}
+
+/// Evens out loud and quiet passages
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compressor {
+    /// Level in dBFS above which the sound is compressed
+    pub threshold: f64,
+    pub ratio: f64,
+    pub attack_ms: f64,
+    pub release_ms: f64,
+    /// Gain in dB after compression, to make up for the lost level
+    pub makeup: f64,
+    sample_rate: f64,
+    /// Smoothed level in dBFS
+    envelope: f64,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self {
+            threshold: -20.0,
+            ratio: 4.0,
+            attack_ms: 10.0,
+            release_ms: 150.0,
+            makeup: 6.0,
+            sample_rate: 48000.0,
+            envelope: -120.0,
+        }
+    }
+}
+
+impl Dsp for Compressor {
+    fn name(&self) -> &'static str {
+        "Compressor"
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![
+            Parameter::new("Threshold (dB)", &mut self.threshold, -60.0..=0.0),
+            Parameter::new("Ratio", &mut self.ratio, 1.0..=20.0),
+            Parameter::new("Attack (ms)", &mut self.attack_ms, 0.1..=100.0),
+            Parameter::new("Release (ms)", &mut self.release_ms, 10.0..=1000.0),
+            Parameter::new("Makeup gain (dB)", &mut self.makeup, 0.0..=24.0),
+        ]
+    }
+
+    fn init(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn process(&mut self, frame: Frame) -> Frame {
+        let level = gain_to_db(frame.left.abs().max(frame.right.abs()) as f64);
+        let coefficient = if level > self.envelope {
+            smoothing(self.attack_ms, self.sample_rate)
+        } else {
+            smoothing(self.release_ms, self.sample_rate)
+        };
+        self.envelope = level + coefficient * (self.envelope - level);
+
+        let over = (self.envelope - self.threshold).max(0.0);
+        let reduction = over * (1.0 - 1.0 / self.ratio.max(1.0));
+        let gain = db_to_gain(self.makeup - reduction) as f32;
+        Frame::new(frame.left * gain, frame.right * gain)
+    }
+
+    fn carry_state(&mut self, previous: &dyn Dsp) {
+        if let Some(previous) = downcast::<Self>(previous) {
+            self.envelope = previous.envelope;
+        }
+    }
+}
+
+/// Keeps peaks below a ceiling, so nothing clips
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limiter {
+    /// Highest level in dBFS to let through
+    pub ceiling: f64,
+    pub release_ms: f64,
+    sample_rate: f64,
+    gain: f64,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            ceiling: -1.0,
+            release_ms: 80.0,
+            sample_rate: 48000.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Dsp for Limiter {
+    fn name(&self) -> &'static str {
+        "Limiter"
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![
+            Parameter::new("Ceiling (dB)", &mut self.ceiling, -12.0..=0.0),
+            Parameter::new("Release (ms)", &mut self.release_ms, 10.0..=500.0),
+        ]
+    }
+
+    fn init(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn process(&mut self, frame: Frame) -> Frame {
+        let peak = frame.left.abs().max(frame.right.abs()) as f64;
+        let ceiling = db_to_gain(self.ceiling);
+        let needed = if peak > ceiling { ceiling / peak } else { 1.0 };
+        // clamp down at once, recover slowly
+        self.gain = if needed < self.gain {
+            needed
+        } else {
+            needed + smoothing(self.release_ms, self.sample_rate) * (self.gain - needed)
+        };
+        let gain = self.gain as f32;
+        Frame::new(frame.left * gain, frame.right * gain)
+    }
+
+    fn carry_state(&mut self, previous: &dyn Dsp) {
+        if let Some(previous) = downcast::<Self>(previous) {
+            self.gain = previous.gain;
+        }
+    }
+}
+
+/// Makes the stereo image wider or narrower
+#[derive(Debug, Clone, PartialEq)]
+pub struct Widener {
+    /// 0 is mono, 1 leaves the sound as it is, above 1 widens it
+    pub width: f64,
+}
+
+impl Default for Widener {
+    fn default() -> Self {
+        Self { width: 1.5 }
+    }
+}
+
+impl Dsp for Widener {
+    fn name(&self) -> &'static str {
+        "Stereo widener"
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![Parameter::new("Width", &mut self.width, 0.0..=3.0)]
+    }
+
+    fn process(&mut self, frame: Frame) -> Frame {
+        let mid = (frame.left + frame.right) / 2.0;
+        let side = (frame.left - frame.right) / 2.0 * self.width as f32;
+        Frame::new(mid + side, mid - side)
+    }
+}
+
+/// Plays the same signal on both sides, for a single earbud
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Downmix;
+
+impl Dsp for Downmix {
+    fn name(&self) -> &'static str {
+        "Mono downmix"
+    }
+
+    fn process(&mut self, frame: Frame) -> Frame {
+        Frame::from_mono((frame.left + frame.right) / 2.0)
+    }
+}
+
+/// Shifts the sound to the left or right
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Balance {
+    /// -1 is left only, 1 is right only
+    pub balance: f64,
+}
+
+impl Dsp for Balance {
+    fn name(&self) -> &'static str {
+        "Balance"
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![Parameter::new(
+            "Left / right",
+            &mut self.balance,
+            -1.0..=1.0,
+        )]
+    }
+
+    fn process(&mut self, frame: Frame) -> Frame {
+        let balance = self.balance.clamp(-1.0, 1.0) as f32;
+        Frame::new(
+            frame.left * (1.0 - balance).min(1.0),
+            frame.right * (1.0 + balance).min(1.0),
+        )
+    }
+}
+
+#[cfg_attr(
+    feature = "persistence",
+    derive(Deserialize, Serialize),
+    serde(try_from = "SavedSlot", into = "SavedSlot")
+)]
+#[derive(Debug, Clone)]
+pub struct ChainSlot {
+    pub enabled: bool,
+    pub effect: Box<dyn Dsp>,
+}
+
+impl ChainSlot {
+    pub fn new(effect: Box<dyn Dsp>) -> Self {
+        Self {
+            enabled: true,
+            effect,
+        }
+    }
+}
+
+impl PartialEq for ChainSlot {
+    fn eq(&self, other: &Self) -> bool {
+        self.enabled == other.enabled
+            && self.effect.name() == other.effect.name()
+            && values(self.effect.as_ref()) == values(other.effect.as_ref())
+    }
+}
+
+/// A slot as it is saved, with the effect by name and its settings by their names
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone)]
+struct SavedSlot {
+    enabled: bool,
+    effect: String,
+    parameters: Vec<(String, f64)>,
+}
+
+impl From<ChainSlot> for SavedSlot {
+    fn from(slot: ChainSlot) -> Self {
+        Self {
+            enabled: slot.enabled,
+            effect: slot.effect.name().to_string(),
+            parameters: values(slot.effect.as_ref())
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<SavedSlot> for ChainSlot {
+    type Error = Error;
+
+    fn try_from(saved: SavedSlot) -> Result<Self, Error> {
+        let mut effect =
+            create(&saved.effect).ok_or_else(|| anyhow!("No effect {}", saved.effect))?;
+        // settings that are gone keep their default
+        for parameter in effect.parameters() {
+            if let Some((_, value)) = saved.parameters.iter().find(|(n, _)| n == parameter.name) {
+                *parameter.value = *value;
+            }
+        }
+        Ok(Self {
+            enabled: saved.enabled,
+            effect,
+        })
+    }
+}
+
+/// Changes a running chain
+#[derive(Debug)]
+pub struct DspChainHandle {
+    updates: Sender<Vec<ChainSlot>>,
+    /// Chains the audio thread is done with, to free them here
+    replaced: Receiver<Vec<ChainSlot>>,
+    slots: Vec<ChainSlot>,
+}
+
+impl DspChainHandle {
+    pub fn slots(&self) -> &[ChainSlot] {
+        &self.slots
+    }
+
+    pub fn set_slots(&mut self, slots: &[ChainSlot]) {
+        self.replaced.try_iter().for_each(drop);
+        self.slots = slots.to_vec();
+        let _ = self.updates.send(self.slots.clone());
+    }
+}
+
+/// Runs the chain on the audio thread, as an effect of a kira track
+#[derive(Debug)]
+pub struct DspChain {
+    updates: Receiver<Vec<ChainSlot>>,
+    /// Freeing memory could block the audio thread, so replaced chains go back
+    replaced: Sender<Vec<ChainSlot>>,
+    slots: Vec<ChainSlot>,
+    sample_rate: f64,
+}
+
+/// An empty chain, and the handle to fill it once it runs
+pub fn dsp_chain() -> (DspChain, DspChainHandle) {
+    let (updates, receiver) = channel();
+    let (replaced, replaced_receiver) = channel();
+    let chain = DspChain {
+        updates: receiver,
+        replaced,
+        slots: vec![],
+        sample_rate: 48000.0,
+    };
+    (
+        chain,
+        DspChainHandle {
+            updates,
+            replaced: replaced_receiver,
+            slots: vec![],
+        },
+    )
+}
+
+impl DspChain {
+    fn update(&mut self) {
+        while let Ok(mut slots) = self.updates.try_recv() {
+            for (i, slot) in slots.iter_mut().enumerate() {
+                slot.effect.init(self.sample_rate);
+                if let Some(previous) = self.slots.get(i) {
+                    slot.effect.carry_state(previous.effect.as_ref());
+                }
+            }
+            let previous = std::mem::replace(&mut self.slots, slots);
+            let _ = self.replaced.send(previous);
+        }
+    }
+}
+
+impl Effect for DspChain {
+    fn init(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate as f64;
+        for slot in &mut self.slots {
+            slot.effect.init(self.sample_rate);
+        }
+    }
+
+    fn process(&mut self, _dt: f64, input: Frame, _parameters: &Parameters) -> Frame {
+        self.update();
+        self.slots
+            .iter_mut()
+            .filter(|s| s.enabled)
+            .fold(input, |frame, slot| slot.effect.process(frame))
+    }
+}
+
+/// How the two channels reach the speakers
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[serde(default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelSettings {
+    /// -1 is left only, 1 is right only
+    pub balance: f64,
+    /// Fold both channels into one, for listening with a single earbud
+    pub mono: bool,
+    /// Swap left and right
+    pub swap: bool,
+}
+
+impl ChannelSettings {
+    fn process(&self, frame: Frame) -> Frame {
+        let frame = if self.swap {
+            Frame::new(frame.right, frame.left)
+        } else {
+            frame
+        };
+        let frame = if self.mono {
+            Downmix.process(frame)
+        } else {
+            frame
+        };
+        Balance {
+            balance: self.balance,
+        }
+        .process(frame)
+    }
+}
+
+/// Time to blend night mode in or out, so switching it does not click
+const NIGHT_MODE_RAMP: f64 = 0.05;
+
+/// Master volume and night mode, shared with the audio thread
+#[derive(Debug)]
+struct SharedOutput {
+    volume: AtomicU64,
+    night_mode: AtomicBool,
+    balance: AtomicU64,
+    mono: AtomicBool,
+    swap: AtomicBool,
+}
+
+impl SharedOutput {
+    fn channels(&self) -> ChannelSettings {
+        ChannelSettings {
+            balance: f64::from_bits(self.balance.load(Ordering::Relaxed)),
+            mono: self.mono.load(Ordering::Relaxed),
+            swap: self.swap.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Changes a running output stage
+#[derive(Debug, Clone)]
+pub struct OutputHandle {
+    shared: Arc<SharedOutput>,
+}
+
+impl OutputHandle {
+    pub fn volume(&self) -> f64 {
+        f64::from_bits(self.shared.volume.load(Ordering::Relaxed))
+    }
+
+    pub fn set_volume(&mut self, volume: f64) {
+        self.shared
+            .volume
+            .store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn night_mode(&self) -> bool {
+        self.shared.night_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn set_night_mode(&mut self, night_mode: bool) {
+        self.shared.night_mode.store(night_mode, Ordering::Relaxed);
+    }
+
+    pub fn channels(&self) -> ChannelSettings {
+        self.shared.channels()
+    }
+
+    pub fn set_channels(&mut self, channels: ChannelSettings) {
+        self.shared
+            .balance
+            .store(channels.balance.to_bits(), Ordering::Relaxed);
+        self.shared.mono.store(channels.mono, Ordering::Relaxed);
+        self.shared.swap.store(channels.swap, Ordering::Relaxed);
+    }
+}
+
+/// The last effect on the main track. Routes the channels, applies the master volume and,
+/// in night mode, evens out quiet and loud passages and limits whatever the volume pushed
+/// above full scale.
+#[derive(Debug)]
+pub struct Output {
+    shared: Arc<SharedOutput>,
+    compressor: Compressor,
+    limiter: Limiter,
+    /// How much of night mode is blended in, ramped towards the toggle
+    mix: f64,
+    sample_rate: f64,
+}
+
+/// An output stage at full volume with the channels as they are, and the handle to change
+/// it once it runs
+pub fn output() -> (Output, OutputHandle) {
+    let shared = Arc::new(SharedOutput {
+        volume: AtomicU64::new(1f64.to_bits()),
+        night_mode: AtomicBool::new(false),
+        balance: AtomicU64::new(0f64.to_bits()),
+        mono: AtomicBool::new(false),
+        swap: AtomicBool::new(false),
+    });
+    let output = Output {
+        shared: shared.clone(),
+        // tuned for quiet listening: whispers come up, peaks come down
+        compressor: Compressor {
+            threshold: -32.0,
+            ratio: 6.0,
+            attack_ms: 5.0,
+            release_ms: 250.0,
+            makeup: 14.0,
+            ..Compressor::default()
+        },
+        limiter: Limiter {
+            ceiling: -1.0,
+            release_ms: 50.0,
+            ..Limiter::default()
+        },
+        mix: 0.0,
+        sample_rate: 48000.0,
+    };
+    (output, OutputHandle { shared })
+}
+
+impl Effect for Output {
+    fn init(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate as f64;
+        self.compressor.init(self.sample_rate);
+        self.limiter.init(self.sample_rate);
+    }
+
+    fn process(&mut self, dt: f64, input: Frame, _parameters: &Parameters) -> Frame {
+        let input = self.shared.channels().process(input);
+        let volume = f64::from_bits(self.shared.volume.load(Ordering::Relaxed)) as f32;
+        let target = if self.shared.night_mode.load(Ordering::Relaxed) {
+            1.0
+        } else {
+            0.0
+        };
+        let step = dt / NIGHT_MODE_RAMP;
+        self.mix = if self.mix < target {
+            (self.mix + step).min(target)
+        } else {
+            (self.mix - step).max(target)
+        };
+
+        let dry = input * volume;
+        if self.mix == 0.0 {
+            return dry;
+        }
+        // volume goes between the two, so the compressor does not undo it
+        let compressed = self.compressor.process(input) * volume;
+        let wet = self.limiter.process(compressed);
+        let mix = self.mix as f32;
+        dry * (1.0 - mix) + wet * mix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressor(threshold: f64) -> ChainSlot {
+        ChainSlot::new(Box::new(Compressor {
+            threshold,
+            ..Compressor::default()
+        }))
+    }
+
+    #[test]
+    fn slots_are_saved_with_their_settings() {
+        let slot = compressor(-30.0);
+        let saved = SavedSlot::from(slot.clone());
+        assert_eq!(saved.effect, "Compressor");
+        assert!(saved
+            .parameters
+            .contains(&("Threshold (dB)".to_string(), -30.0)));
+        assert_eq!(ChainSlot::try_from(saved).unwrap(), slot);
+        assert_ne!(compressor(-10.0), slot);
+
+        let unknown = SavedSlot {
+            enabled: true,
+            effect: "Reverb".to_string(),
+            parameters: vec![],
+        };
+        assert!(ChainSlot::try_from(unknown).is_err());
+    }
+
+    #[test]
+    fn every_effect_can_be_restored() {
+        for effect in available() {
+            let slot = ChainSlot::new(effect);
+            assert_eq!(
+                ChainSlot::try_from(SavedSlot::from(slot.clone())).unwrap(),
+                slot
+            );
+        }
+    }
+
+    #[test]
+    fn replaced_chains_are_freed_on_the_ui_thread() {
+        let (mut chain, mut handle) = dsp_chain();
+        handle.set_slots(&[compressor(-20.0)]);
+        chain.update();
+        assert_eq!(handle.replaced.try_iter().count(), 1);
+
+        handle.set_slots(&[compressor(-30.0)]);
+        handle.set_slots(&[compressor(-40.0)]);
+        chain.update();
+        // the chain in between never ran, and goes back as well
+        assert_eq!(handle.replaced.try_iter().count(), 2);
+        assert_eq!(chain.slots, [compressor(-40.0)]);
+    }
+
+    #[test]
+    fn changing_a_setting_keeps_the_state() {
+        let (mut chain, mut handle) = dsp_chain();
+        handle.set_slots(&[compressor(-20.0)]);
+        chain.update();
+        for _ in 0..4800 {
+            chain.slots[0].effect.process(Frame::from_mono(0.9));
+        }
+        let envelope = |chain: &DspChain| {
+            downcast::<Compressor>(chain.slots[0].effect.as_ref())
+                .unwrap()
+                .envelope
+        };
+        let before = envelope(&chain);
+        assert!(before > -10.0);
+
+        handle.set_slots(&[compressor(-30.0)]);
+        chain.update();
+        assert_eq!(envelope(&chain), before);
+    }
+}
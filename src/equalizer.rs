@@ -0,0 +1,192 @@
+//! A 10 band graphic equalizer on the main track
+
+use kira::{mixer::effect::Effect, parameter::Parameters, Frame};
+#[cfg(feature = "persistence")]
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::biquad::Biquad;
+
+/// Center frequencies of the bands, an octave apart
+pub const BANDS: [f64; 10] = [
+    31.25, 62.5, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// Lowest and highest gain of a band in dB
+pub const MAX_GAIN: f64 = 12.0;
+
+/// Gain of each band in dB
+pub type Gains = [f64; BANDS.len()];
+
+/// Bandwidth of about an octave, so neighbouring bands blend into each other
+const Q: f64 = 1.41;
+
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct EqPreset {
+    pub name: String,
+    pub gains: Gains,
+}
+
+impl EqPreset {
+    fn new(name: &str, gains: Gains) -> Self {
+        Self {
+            name: name.to_string(),
+            gains,
+        }
+    }
+}
+
+/// Presets that come with the player
+pub fn builtin_presets() -> Vec<EqPreset> {
+    vec![
+        EqPreset::new("Flat", [0.0; BANDS.len()]),
+        // less rumble and boom, more consonants
+        EqPreset::new(
+            "Voice clarity",
+            [-8.0, -6.0, -3.0, -1.0, 0.0, 2.0, 4.0, 4.0, 2.0, 0.0],
+        ),
+        EqPreset::new(
+            "Bass boost",
+            [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ),
+        EqPreset::new(
+            "Treble boost",
+            [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 4.0, 5.0, 6.0],
+        ),
+        EqPreset::new(
+            "Bass and treble",
+            [5.0, 4.0, 2.0, 0.0, -1.0, -1.0, 0.0, 2.0, 4.0, 5.0],
+        ),
+    ]
+}
+
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[serde(default)]
+#[derive(Debug, Clone, Default)]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    pub gains: Gains,
+    /// Presets saved by the user
+    pub presets: Vec<EqPreset>,
+    /// Name to save the current gains under
+    #[serde(skip)]
+    pub preset_name: String,
+}
+
+impl EqualizerSettings {
+    /// The gains to apply, flat while switched off
+    pub fn effective(&self) -> Gains {
+        if self.enabled {
+            self.gains
+        } else {
+            [0.0; BANDS.len()]
+        }
+    }
+
+    /// Save the current gains, replacing a user preset of the same name
+    pub fn save_preset(&mut self, name: &str) {
+        let preset = EqPreset::new(name, self.gains);
+        match self.presets.iter_mut().find(|p| p.name == name) {
+            Some(existing) => *existing = preset,
+            None => self.presets.push(preset),
+        }
+    }
+
+    pub fn delete_preset(&mut self, name: &str) {
+        self.presets.retain(|p| p.name != name);
+    }
+}
+
+/// Band gains in dB, shared with the audio thread as f64 bits
+#[derive(Debug, Default)]
+struct SharedGains([AtomicU64; BANDS.len()]);
+
+/// Changes the gains of a running equalizer
+#[derive(Debug, Clone)]
+pub struct EqualizerHandle {
+    gains: Arc<SharedGains>,
+}
+
+impl EqualizerHandle {
+    pub fn gains(&self) -> Gains {
+        let mut gains = [0.0; BANDS.len()];
+        for (gain, shared) in gains.iter_mut().zip(&self.gains.0) {
+            *gain = f64::from_bits(shared.load(Ordering::Relaxed));
+        }
+        gains
+    }
+
+    pub fn set_gains(&mut self, gains: Gains) {
+        for (gain, shared) in gains.iter().zip(&self.gains.0) {
+            shared.store(gain.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// The effect that runs on the audio thread, one peaking filter per band and channel
+#[derive(Debug)]
+pub struct EqualizerEffect {
+    gains: Arc<SharedGains>,
+    /// Gains the filters are set up for
+    applied: Gains,
+    sample_rate: f64,
+    filters: [[Biquad; BANDS.len()]; 2],
+}
+
+/// A flat equalizer, and the handle to change it once it runs
+pub fn equalizer() -> (EqualizerEffect, EqualizerHandle) {
+    let gains = Arc::new(SharedGains::default());
+    let sample_rate = 48000.0;
+    let mut filters = [[Biquad::new([1.0, 0.0, 0.0], [1.0, 0.0, 0.0]); BANDS.len()]; 2];
+    for channel in filters.iter_mut() {
+        for (filter, freq) in channel.iter_mut().zip(&BANDS) {
+            *filter = Biquad::peaking(sample_rate, *freq, Q, 0.0);
+        }
+    }
+    let effect = EqualizerEffect {
+        gains: gains.clone(),
+        applied: [0.0; BANDS.len()],
+        sample_rate,
+        filters,
+    };
+    (effect, EqualizerHandle { gains })
+}
+
+impl EqualizerEffect {
+    /// Follow gain changes, recomputing only the bands that changed
+    fn update(&mut self, force: bool) {
+        for band in 0..BANDS.len() {
+            let gain = f64::from_bits(self.gains.0[band].load(Ordering::Relaxed));
+            if gain == self.applied[band] && !force {
+                continue;
+            }
+            self.applied[band] = gain;
+            for channel in self.filters.iter_mut() {
+                channel[band].set_peaking(self.sample_rate, BANDS[band], Q, gain);
+            }
+        }
+    }
+}
+
+impl Effect for EqualizerEffect {
+    fn init(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate as f64;
+        self.update(true);
+    }
+
+    fn process(&mut self, _dt: f64, input: Frame, _parameters: &Parameters) -> Frame {
+        self.update(false);
+        let [left, right] = &mut self.filters;
+        let left = left
+            .iter_mut()
+            .fold(input.left as f64, |x, filter| filter.process(x));
+        let right = right
+            .iter_mut()
+            .fold(input.right as f64, |x, filter| filter.process(x));
+        Frame::new(left as f32, right as f32)
+    }
+}
@@ -0,0 +1,597 @@
+//! Loudness normalization, so quiet and loud files play at a similar level
+
+use anyhow::Result;
+use kira::Frame;
+use log::{debug, info};
+#[cfg(feature = "persistence")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    f64::consts::PI,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey, Tag},
+    probe::Hint,
+};
+
+use crate::{biquad::Biquad, stream};
+
+/// Loudness that ReplayGain 2.0 normalizes to, in LUFS
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Which ReplayGain value to apply
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GainMode {
+    /// Off unless chosen, as it also starts measuring untagged files in the background
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// Album gain when playing in order, track gain while shuffling
+    Auto,
+}
+
+impl GainMode {
+    /// The mode that applies, resolving `Auto`
+    pub fn resolve(self, shuffled: bool) -> Self {
+        match self {
+            GainMode::Auto if shuffled => GainMode::Track,
+            GainMode::Auto => GainMode::Album,
+            mode => mode,
+        }
+    }
+}
+
+/// ReplayGain values from the tags of a file. Gains are in dB, peaks relative to full scale.
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    /// Whether the file has any gain tags
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+        let mut probed = symphonia::default::get_probe().format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+
+        let mut gain = Self::default();
+        // tags in front of the container, like ID3, then those of the container itself
+        if let Some(mut metadata) = probed.metadata.get() {
+            if let Some(revision) = metadata.skip_to_latest() {
+                gain.read_revision(revision);
+            }
+        }
+        if let Some(revision) = probed.format.metadata().skip_to_latest() {
+            gain.read_revision(revision);
+        }
+        Ok(gain)
+    }
+
+    fn read_revision(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = match parse_value(&tag.value.to_string()) {
+                Some(v) => v,
+                None => continue,
+            };
+            match tag_key(tag) {
+                Some(StandardTagKey::ReplayGainTrackGain) => self.track_gain = Some(value),
+                Some(StandardTagKey::ReplayGainTrackPeak) => self.track_peak = Some(value),
+                Some(StandardTagKey::ReplayGainAlbumGain) => self.album_gain = Some(value),
+                Some(StandardTagKey::ReplayGainAlbumPeak) => self.album_peak = Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    /// Volume factor to apply. Album values fall back to track values and vice versa.
+    /// Never boosts a file so far that its peak would clip.
+    pub fn factor(&self, mode: GainMode) -> f64 {
+        let (gain, peak) = match mode {
+            GainMode::Off => return 1.0,
+            GainMode::Track | GainMode::Auto => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+            GainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+        };
+        let factor = gain.map(|g| 10f64.powf(g / 20.0)).unwrap_or(1.0);
+        let factor = match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        };
+        // a broken value would turn the whole output into NaN
+        if factor.is_finite() {
+            factor
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Containers without a standard mapping, like MP4, keep the tag name as the key
+fn tag_key(tag: &Tag) -> Option<StandardTagKey> {
+    if tag.std_key.is_some() {
+        return tag.std_key;
+    }
+    let key = tag.key.to_lowercase();
+    if key.ends_with("replaygain_track_gain") {
+        Some(StandardTagKey::ReplayGainTrackGain)
+    } else if key.ends_with("replaygain_track_peak") {
+        Some(StandardTagKey::ReplayGainTrackPeak)
+    } else if key.ends_with("replaygain_album_gain") {
+        Some(StandardTagKey::ReplayGainAlbumGain)
+    } else if key.ends_with("replaygain_album_peak") {
+        Some(StandardTagKey::ReplayGainAlbumPeak)
+    } else {
+        None
+    }
+}
+
+/// Parses values like "-6.48 dB" or "0.988525"
+fn parse_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse().ok()
+}
+
+/// Result of analyzing a file according to EBU R128
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// True peak relative to full scale
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// Decode and measure a whole file. This takes a while, so it is best done in the background.
+    pub fn analyze<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let (sample_rate, channels, packets) = stream::decode(path)?;
+        let mut meter = LoudnessMeter::new(sample_rate, channels);
+        for frames in packets {
+            for frame in frames? {
+                meter.push(frame);
+            }
+        }
+        Ok(meter.loudness())
+    }
+
+    /// Track gain bringing the file to the ReplayGain reference level.
+    /// Silent or very short files have no loudness to bring there, and get no gain.
+    pub fn replaygain(&self) -> ReplayGain {
+        if !self.integrated.is_finite() {
+            return ReplayGain::default();
+        }
+        ReplayGain {
+            track_gain: Some(REFERENCE_LOUDNESS - self.integrated),
+            track_peak: Some(self.true_peak),
+            ..ReplayGain::default()
+        }
+    }
+}
+
+/// The K-weighting of ITU-R BS.1770: a high shelf for the head, then a high pass.
+/// The coefficients are derived for any sample rate, the standard only lists them for 48kHz.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+/// Oversampling factor for finding peaks between samples
+const OVERSAMPLING: usize = 4;
+/// Input samples each interpolated sample is computed from
+const INTERPOLATION_TAPS: usize = 12;
+
+/// Estimates the peak of the reconstructed waveform, which can lie between samples
+#[derive(Debug, Clone)]
+struct TruePeak {
+    /// One interpolation filter per position between two samples
+    phases: Vec<[f64; INTERPOLATION_TAPS]>,
+    history: [f64; INTERPOLATION_TAPS],
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new() -> Self {
+        let len = OVERSAMPLING * INTERPOLATION_TAPS;
+        let center = (INTERPOLATION_TAPS / 2) as f64;
+        let phases = (0..OVERSAMPLING)
+            .map(|phase| {
+                let mut taps = [0.0; INTERPOLATION_TAPS];
+                for (k, tap) in taps.iter_mut().enumerate() {
+                    // time of this input sample relative to the interpolated one
+                    let t = k as f64 - center + phase as f64 / OVERSAMPLING as f64;
+                    let sinc = if t == 0.0 {
+                        1.0
+                    } else {
+                        (PI * t).sin() / (PI * t)
+                    };
+                    let i = k * OVERSAMPLING + OVERSAMPLING - phase;
+                    let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / len as f64).cos();
+                    *tap = sinc * window;
+                }
+                taps
+            })
+            .collect();
+        Self {
+            phases,
+            history: [0.0; INTERPOLATION_TAPS],
+            peak: 0.0,
+        }
+    }
+
+    fn push(&mut self, x: f64) {
+        self.history.rotate_right(1);
+        self.history[0] = x;
+        self.peak = self.peak.max(x.abs());
+        for taps in &self.phases {
+            let y: f64 = taps.iter().zip(&self.history).map(|(t, x)| t * x).sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+}
+
+/// Measures integrated loudness and true peak of mono or stereo audio, following EBU R128
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    /// Channels measured. Mono comes as the same signal on both sides, but counts once.
+    channels: usize,
+    filters: [[Biquad; 2]; 2],
+    peaks: [TruePeak; 2],
+    /// Frames in 100ms, a quarter of a gating block
+    step_len: usize,
+    /// Sum of weighted squares in the current step
+    step_sum: f64,
+    step_frames: usize,
+    /// Sums of the last four steps
+    steps: Vec<f64>,
+    /// Mean square of each complete 400ms block
+    blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let weighting = k_weighting(sample_rate);
+        Self {
+            channels: if channels == 1 { 1 } else { 2 },
+            filters: [weighting, weighting],
+            peaks: [TruePeak::new(), TruePeak::new()],
+            step_len: (sample_rate as usize / 10).max(1),
+            step_sum: 0.0,
+            step_frames: 0,
+            steps: vec![],
+            blocks: vec![],
+        }
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        let samples = [frame.left, frame.right];
+        for (channel, sample) in samples.iter().take(self.channels).enumerate() {
+            let sample = *sample as f64;
+            self.peaks[channel].push(sample);
+            let weighted = self.filters[channel]
+                .iter_mut()
+                .fold(sample, |x, filter| filter.process(x));
+            self.step_sum += weighted * weighted;
+        }
+
+        self.step_frames += 1;
+        if self.step_frames == self.step_len {
+            // blocks are 400ms long and overlap by 75%
+            self.steps.push(self.step_sum);
+            if self.steps.len() > 4 {
+                self.steps.remove(0);
+            }
+            if self.steps.len() == 4 {
+                let sum: f64 = self.steps.iter().sum();
+                self.blocks.push(sum / (4 * self.step_len) as f64);
+            }
+            self.step_sum = 0.0;
+            self.step_frames = 0;
+        }
+    }
+
+    /// Integrated loudness in LUFS, or negative infinity for silence or very short audio
+    pub fn integrated(&self) -> f64 {
+        let loudness = |mean_square: f64| -0.691 + 10.0 * mean_square.log10();
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+        let audible = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|b| loudness(*b) > -70.0)
+            .collect::<Vec<_>>();
+        if audible.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        let relative_gate = loudness(mean(&audible)) - 10.0;
+        let gated = audible
+            .into_iter()
+            .filter(|b| loudness(*b) > relative_gate)
+            .collect::<Vec<_>>();
+        loudness(mean(&gated))
+    }
+
+    pub fn true_peak(&self) -> f64 {
+        self.peaks[0].peak.max(self.peaks[1].peak)
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: self.integrated(),
+            true_peak: self.true_peak(),
+        }
+    }
+}
+
+/// Analyzes files one after another on a background thread
+#[derive(Debug)]
+pub struct LoudnessScanner {
+    jobs: Sender<PathBuf>,
+    results: Receiver<(PathBuf, Option<Loudness>)>,
+    /// Every file ever handed to the scanner, so none is analyzed twice
+    requested: HashSet<PathBuf>,
+    /// Files in the current batch, and how many of them are done
+    queued: usize,
+    done: usize,
+}
+
+impl LoudnessScanner {
+    pub fn new() -> Result<Self> {
+        let (jobs, job_receiver) = channel::<PathBuf>();
+        let (result_sender, results) = channel();
+        thread::Builder::new()
+            .name("loudness scanner".into())
+            .spawn(move || {
+                for path in job_receiver {
+                    let loudness = match Loudness::analyze(&path) {
+                        Ok(loudness) => Some(loudness),
+                        Err(e) => {
+                            debug!("Can't analyze {}: {}", path.display(), e);
+                            None
+                        }
+                    };
+                    if result_sender.send((path, loudness)).is_err() {
+                        return;
+                    }
+                }
+            })?;
+        Ok(Self {
+            jobs,
+            results,
+            requested: HashSet::new(),
+            queued: 0,
+            done: 0,
+        })
+    }
+
+    /// Queue a file for analysis, unless it was queued before
+    pub fn scan(&mut self, path: &Path) {
+        if self.requested.insert(path.to_path_buf()) && self.jobs.send(path.into()).is_ok() {
+            self.queued += 1;
+        }
+    }
+
+    /// Files analyzed since the last call. Failed files are left out.
+    pub fn finished(&mut self) -> Vec<(PathBuf, Loudness)> {
+        let results = self.results.try_iter().collect::<Vec<_>>();
+        self.done += results.len();
+        if self.done == self.queued {
+            self.done = 0;
+            self.queued = 0;
+        }
+        results
+            .into_iter()
+            .filter_map(|(path, loudness)| {
+                let loudness = loudness?;
+                info!(
+                    "{}: {:.1} LUFS, peak {:.2}",
+                    path.display(),
+                    loudness.integrated,
+                    loudness.true_peak
+                );
+                Some((path, loudness))
+            })
+            .collect()
+    }
+
+    /// Files done and files queued in the current batch, while there is one
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        if self.queued > 0 {
+            Some((self.done, self.queued))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(sample_rate: u32, frames: impl Iterator<Item = Frame>) -> Loudness {
+        let mut meter = LoudnessMeter::new(sample_rate, 2);
+        frames.for_each(|f| meter.push(f));
+        meter.loudness()
+    }
+
+    /// A stereo sine with the given peak level in dBFS
+    fn sine(
+        sample_rate: u32,
+        freq: f64,
+        level: f64,
+        phase: f64,
+        secs: f64,
+    ) -> impl Iterator<Item = Frame> {
+        let amplitude = 10f64.powf(level / 20.0);
+        (0..(secs * sample_rate as f64) as usize).map(move |i| {
+            let t = i as f64 / sample_rate as f64;
+            Frame::from_mono((amplitude * (2.0 * PI * freq * t + phase).sin()) as f32)
+        })
+    }
+
+    fn silence(sample_rate: u32, secs: f64) -> impl Iterator<Item = Frame> {
+        (0..(secs * sample_rate as f64) as usize).map(|_| Frame::from_mono(0.0))
+    }
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn reference_sine() {
+        // EBU Tech 3341 case 1: a 1kHz stereo sine at -23 dBFS reads -23 LUFS
+        let loudness = measure(48000, sine(48000, 1000.0, -23.0, 0.0, 20.0));
+        assert_near(loudness.integrated, -23.0, 0.1);
+        // the same tone 10 dB louder
+        let loudness = measure(48000, sine(48000, 1000.0, -13.0, 0.0, 5.0));
+        assert_near(loudness.integrated, -13.0, 0.1);
+    }
+
+    #[test]
+    fn other_sample_rate() {
+        let loudness = measure(44100, sine(44100, 1000.0, -20.0, 0.0, 5.0));
+        assert_near(loudness.integrated, -20.0, 0.1);
+    }
+
+    #[test]
+    fn silence_is_gated() {
+        let tone = measure(48000, sine(48000, 1000.0, -20.0, 0.0, 20.0));
+        let with_pauses = measure(
+            48000,
+            silence(48000, 5.0)
+                .chain(sine(48000, 1000.0, -20.0, 0.0, 20.0))
+                .chain(silence(48000, 5.0)),
+        );
+        // only the few blocks overlapping both tone and silence count in
+        assert_near(with_pauses.integrated, tone.integrated, 0.1);
+        assert!(measure(48000, silence(48000, 2.0)).integrated.is_infinite());
+    }
+
+    #[test]
+    fn quiet_parts_are_gated() {
+        // EBU Tech 3341 case 3: -36, -23 and -36 dBFS. The quiet parts are more than 10 LU below.
+        let loudness = measure(
+            48000,
+            sine(48000, 1000.0, -36.0, 0.0, 10.0)
+                .chain(sine(48000, 1000.0, -23.0, 0.0, 60.0))
+                .chain(sine(48000, 1000.0, -36.0, 0.0, 10.0)),
+        );
+        assert_near(loudness.integrated, -23.0, 0.1);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // at a quarter of the sample rate and shifted by 45°, every sample misses the peak by 3 dB
+        let frames = sine(48000, 12000.0, -6.0, PI / 4.0, 1.0).collect::<Vec<_>>();
+        let sample_peak = frames.iter().fold(0f32, |p, f| p.max(f.left.abs())) as f64;
+        let expected = 10f64.powf(-6.0 / 20.0);
+        assert_near(sample_peak, expected / 2f64.sqrt(), 0.01);
+
+        let loudness = measure(48000, frames.into_iter());
+        assert_near(20.0 * loudness.true_peak.log10(), -6.0, 0.5);
+    }
+
+    #[test]
+    fn replaygain_from_loudness() {
+        let loudness = Loudness {
+            integrated: -12.0,
+            true_peak: 0.9,
+        };
+        let gain = loudness.replaygain();
+        assert_eq!(gain.track_gain, Some(-6.0));
+        assert_near(gain.factor(GainMode::Track), 10f64.powf(-6.0 / 20.0), 1e-9);
+    }
+
+    #[test]
+    fn mono_counts_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mono = Loudness::analyze(crate::testing::mono_tone_wav(dir.path(), "mono.wav", 3.0));
+        let stereo = Loudness::analyze(crate::testing::tone_wav(dir.path(), "stereo.wav", 3.0));
+        let (mono, stereo) = (mono.unwrap(), stereo.unwrap());
+        // the same signal on one channel instead of two is 3 LU quieter
+        assert_near(stereo.integrated - mono.integrated, 3.01, 0.05);
+        assert_near(mono.true_peak, stereo.true_peak, 1e-9);
+    }
+
+    #[test]
+    fn no_gain_for_silent_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = crate::testing::silent_wav(dir.path(), "silence.wav", 1.0);
+        let loudness = Loudness::analyze(path).unwrap();
+        assert!(loudness.integrated.is_infinite());
+        let gain = loudness.replaygain();
+        assert!(gain.is_empty());
+        assert_eq!(gain.factor(GainMode::Auto), 1.0);
+
+        // tags can be just as broken
+        let gain = ReplayGain {
+            track_gain: Some(f64::INFINITY),
+            track_peak: Some(0.0),
+            ..ReplayGain::default()
+        };
+        assert_eq!(gain.factor(GainMode::Track), 1.0);
+    }
+}
@@ -1,8 +1,20 @@
 #![windows_subsystem = "windows"]
 mod app;
+pub mod biquad;
+pub mod dsp;
+pub mod equalizer;
+pub mod loudness;
 #[cfg(target_os = "macos")]
 mod mac;
+pub mod output;
+pub mod player;
+pub mod silence;
+pub mod sleep;
 pub mod sound;
+pub mod stream;
+pub mod stretch;
+#[cfg(test)]
+mod testing;
 pub mod theme;
 pub mod ui_components;
 use log::{info, LevelFilter};
@@ -0,0 +1,302 @@
+//! Plays kira's backend on an output device of our choosing, as kira itself only ever
+//! opens the default device. Without a sound card, it can play into nothing instead.
+
+use anyhow::{anyhow, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use kira::{manager::Backend, Frame};
+use log::{error, info};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Rate kira's backend runs at when we drive it, whatever the device runs at
+pub const BACKEND_SAMPLE_RATE: u32 = 48000;
+
+pub type SharedBackend = Arc<Mutex<Backend>>;
+
+/// Time between attempts to get audio back
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Somewhere the backend plays to
+pub trait AudioOutput: Debug + Send {
+    fn name(&self) -> &str;
+
+    /// Whether it stopped playing for good, and has to be opened again
+    fn is_lost(&self) -> bool {
+        false
+    }
+}
+
+/// What a `StreamManager` plays to
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputKind {
+    /// A sound card by name, or the default one for `None`
+    Device(Option<String>),
+    /// Nothing, at the pace of a sound card. For use without one.
+    Null,
+    /// Nothing, and only as far as `StreamManager::advance` is told.
+    /// Lets tests play through sounds without waiting.
+    Offline,
+}
+
+impl OutputKind {
+    pub fn open(&self, backend: SharedBackend) -> Result<Option<Box<dyn AudioOutput>>> {
+        Ok(match self {
+            OutputKind::Device(device) => {
+                Some(Box::new(DeviceOutput::open(backend, device.as_deref())?))
+            }
+            OutputKind::Null => Some(Box::new(NullOutput::open(backend))),
+            OutputKind::Offline => None,
+        })
+    }
+
+    /// What to play to when this can't be opened. On a system without any sound card,
+    /// that is nothing, so sounds still play through.
+    pub fn fallback(&self, devices: &[String]) -> Option<OutputKind> {
+        match self {
+            OutputKind::Device(_) if devices.is_empty() => Some(OutputKind::Null),
+            _ => None,
+        }
+    }
+}
+
+/// Names of the output devices of the system
+pub fn output_devices() -> Vec<String> {
+    cpal::default_host()
+        .output_devices()
+        .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// An open output device. The stream lives on its own thread, as cpal streams
+/// can't be moved between threads.
+#[derive(Debug)]
+pub struct DeviceOutput {
+    quit: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    /// Name of the device that was opened
+    name: String,
+    /// Set once the device went away, e.g. when it was unplugged
+    lost: Arc<AtomicBool>,
+}
+
+impl DeviceOutput {
+    /// Play `backend` on the device called `device`, or on the default device if there is
+    /// no such device or none is asked for.
+    pub fn open(backend: SharedBackend, device: Option<&str>) -> Result<Self> {
+        let device = device.map(|d| d.to_string());
+        let lost = Arc::new(AtomicBool::new(false));
+        let lost_device = lost.clone();
+        let (quit, quitting) = channel::<()>();
+        let (opened, open_result) = channel();
+        let thread = thread::spawn(move || {
+            let stream = open_stream(backend, device.as_deref(), lost_device);
+            match stream {
+                Ok((stream, name)) => {
+                    let _ = opened.send(Ok(name));
+                    // returns once the sender is dropped
+                    let _ = quitting.recv();
+                    drop(stream);
+                }
+                Err(e) => {
+                    let _ = opened.send(Err(e));
+                }
+            }
+        });
+        let name = open_result
+            .recv()
+            .map_err(|_| anyhow!("Audio output thread went away"))??;
+        info!("Playing on {}", name);
+        Ok(Self {
+            quit: Some(quit),
+            thread: Some(thread),
+            name,
+            lost,
+        })
+    }
+}
+
+impl AudioOutput for DeviceOutput {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the device went away. It stays silent then.
+    fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for DeviceOutput {
+    /// Closes the stream before returning, so it no longer pulls from the backend
+    fn drop(&mut self) {
+        self.quit.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Pulls the backend in real time and throws the audio away
+#[derive(Debug)]
+pub struct NullOutput {
+    quit: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullOutput {
+    /// Frames pulled at once
+    const BLOCK: usize = 480;
+
+    pub fn open(backend: SharedBackend) -> Self {
+        let (quit, quitting) = channel::<()>();
+        let thread = thread::spawn(move || {
+            let start = Instant::now();
+            let mut frames = 0;
+            loop {
+                // catch up with the clock, like a sound card would
+                let due = (start.elapsed().as_secs_f64() * BACKEND_SAMPLE_RATE as f64) as u64;
+                if let Ok(mut backend) = backend.lock() {
+                    while frames < due {
+                        backend.process();
+                        frames += 1;
+                    }
+                }
+                let block =
+                    Duration::from_secs_f64(Self::BLOCK as f64 / BACKEND_SAMPLE_RATE as f64);
+                if quitting.recv_timeout(block) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+            }
+        });
+        info!("Playing without a sound card");
+        Self {
+            quit: Some(quit),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl AudioOutput for NullOutput {
+    fn name(&self) -> &str {
+        "No sound card"
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.quit.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn find_device(host: &cpal::Host, name: Option<&str>) -> Option<cpal::Device> {
+    let named = name.and_then(|name| {
+        host.output_devices()
+            .ok()?
+            .find(|d| d.name().map(|n| n == name).unwrap_or_default())
+    });
+    named.or_else(|| host.default_output_device())
+}
+
+fn open_stream(
+    backend: SharedBackend,
+    name: Option<&str>,
+    lost: Arc<AtomicBool>,
+) -> Result<(cpal::Stream, String)> {
+    let host = cpal::default_host();
+    let device = find_device(&host, name).ok_or(anyhow!("No audio output device"))?;
+    let config = device.default_output_config()?.config();
+    let channels = config.channels as usize;
+    let mut resampler = Resampler::new(BACKEND_SAMPLE_RATE, config.sample_rate.0);
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let mut backend = match backend.lock() {
+                Ok(backend) => backend,
+                Err(_) => {
+                    data.iter_mut().for_each(|s| *s = 0.0);
+                    return;
+                }
+            };
+            for frame in data.chunks_exact_mut(channels) {
+                let out = resampler.next(|| backend.process());
+                if channels == 1 {
+                    frame[0] = (out.left + out.right) / 2.0;
+                } else {
+                    frame[0] = out.left;
+                    frame[1] = out.right;
+                    frame[2..].iter_mut().for_each(|s| *s = 0.0);
+                }
+            }
+        },
+        move |e| {
+            error!("Audio output failed: {}", e);
+            if let cpal::StreamError::DeviceNotAvailable = e {
+                lost.store(true, Ordering::Relaxed);
+            }
+        },
+    )?;
+    stream.play()?;
+    Ok((stream, device.name()?))
+}
+
+/// Paces attempts to get audio back
+#[derive(Debug, Default)]
+pub struct Retry {
+    last_attempt: Option<Instant>,
+}
+
+impl Retry {
+    pub fn attempted(&mut self) {
+        self.last_attempt = Some(Instant::now());
+    }
+
+    /// Whether it is time for the next attempt
+    pub fn due(&self) -> bool {
+        self.last_attempt
+            .map(|t| t.elapsed() >= RETRY_INTERVAL)
+            .unwrap_or(true)
+    }
+}
+
+/// Converts the backend rate to the device rate, by linear interpolation
+struct Resampler {
+    /// Backend frames per device frame
+    step: f64,
+    prev: Frame,
+    cur: Frame,
+    /// Fractional position between `prev` and `cur`
+    phase: f64,
+}
+
+impl Resampler {
+    fn new(from: u32, to: u32) -> Self {
+        Self {
+            step: from as f64 / to as f64,
+            prev: Frame::from_mono(0.0),
+            cur: Frame::from_mono(0.0),
+            phase: 1.0,
+        }
+    }
+
+    fn next(&mut self, mut pull: impl FnMut() -> Frame) -> Frame {
+        while self.phase >= 1.0 {
+            self.prev = self.cur;
+            self.cur = pull();
+            self.phase -= 1.0;
+        }
+        let out = self.prev + (self.cur - self.prev) * self.phase as f32;
+        self.phase += self.step;
+        out
+    }
+}
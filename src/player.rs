@@ -0,0 +1,1109 @@
+//! What plays, what plays next and what to do once it ends, independent of any UI.
+//! Front ends send `Command`s and react to the `Event`s that come back.
+
+use crate::loudness::{GainMode, ReplayGain};
+use crate::sleep::SleepTimer;
+use crate::sound::{
+    MetaSound, Playlist, RepeatMode, ResumePositions, ResumeSettings, ShuffleOrder, SoundQueue,
+    SoundSettings,
+};
+use crate::stream::{StreamHandle, StreamManager, StreamState};
+use log::{debug, info};
+#[cfg(feature = "persistence")]
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Seconds into a sound after which `Command::Prev` starts it over, instead of going back
+pub const RESTART_AFTER: f64 = 3.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Play the active sound, or the first one on the playlist if none is active
+    Play,
+    Pause,
+    Stop,
+    /// Jump to a position in seconds
+    Seek(f64),
+    /// Play the next sound, or stop after the last one
+    Next,
+    /// Start the active sound over, or play the one before it within the first few seconds
+    Prev,
+    /// Make a sound the active one, continuing where it was left
+    PlaySound(MetaSound),
+    /// Add a sound to the end of the playlist, unless it is on it already
+    Enqueue(MetaSound),
+    /// Bookmark the position of the active sound
+    Bookmark,
+    /// Play a sound from a bookmarked position
+    PlayBookmark(MetaSound, f64),
+    /// Add the active sound to the favourites
+    Favourite,
+    /// Take the sound at this index off the playlist
+    Remove(usize),
+    /// Move the sound at index `from` on the playlist to `to`
+    Move {
+        from: usize,
+        to: usize,
+    },
+    SetRepeat(RepeatMode),
+    SetShuffle(bool),
+    /// Playback speed of the active sound, 1.0 is normal
+    SetSpeed(f64),
+    /// Cut long silences in the active sound short
+    SetSkipSilence(bool),
+    /// Start a loop region in the active sound at its position
+    MarkLoopStart,
+    /// End the loop region at the position of the active sound, and start looping
+    MarkLoopEnd,
+    ClearLoop,
+    /// Pause after a while, fading out before. `None` cancels the timer.
+    SetSleepTimer(Option<SleepTimer>),
+    /// Which ReplayGain value to apply, never `Auto`
+    SetGainMode(GainMode),
+    /// Apply the gain of a sound that was measured, as it has no tags
+    SetReplayGain(PathBuf, ReplayGain),
+    /// Time stretch the active sound when it is sped up, instead of resampling it
+    SetPreservePitch(bool),
+    /// Seconds consecutive sounds overlap
+    SetCrossfade(f64),
+    SetResume(ResumeSettings),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Another sound became the active one
+    TrackChanged(MetaSound),
+    /// The active sound moved on, in seconds
+    PositionChanged(f64),
+    /// The active sound played to its end
+    Finished(MetaSound),
+    /// Playback stopped after the last sound of the playlist
+    EndOfQueue,
+}
+
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[serde(default)]
+#[derive(Debug, Default)]
+pub struct Player {
+    active_sound: Option<MetaSound>,
+    /// The sound after the active one, loaded ahead of time
+    #[serde(skip)]
+    preloaded: Option<MetaSound>,
+    queue: SoundQueue,
+    play_count: HashMap<MetaSound, usize>,
+    favourites: HashSet<MetaSound>,
+    bookmarks: HashSet<MetaSound>,
+    /// Seconds consecutive sounds overlap. 0 plays them back to back.
+    crossfade: f64,
+    repeat: RepeatMode,
+    /// Play order while shuffling
+    shuffle: Option<ShuffleOrder>,
+    resume: ResumePositions,
+    /// Speed, loop and skip silence of each sound, wherever it is played from
+    settings: HashMap<PathBuf, SoundSettings>,
+    #[serde(skip)]
+    sleep: Option<SleepTimer>,
+    /// Position of the active sound last reported
+    #[serde(skip)]
+    position: Option<f64>,
+    /// Whether the end of the active sound was reported
+    #[serde(skip)]
+    finished: bool,
+    #[serde(skip)]
+    events: Vec<Event>,
+}
+
+impl Player {
+    pub fn command(&mut self, command: Command, manager: &mut StreamManager) {
+        debug!("{:?}", command);
+        match command {
+            Command::Play => self.play(manager),
+            Command::Pause => {
+                if let Some(h) = self.active_handle() {
+                    h.pause();
+                }
+            }
+            Command::Stop => {
+                if let Some(s) = &mut self.active_sound {
+                    s.stop();
+                    self.resume.remember(s);
+                }
+            }
+            Command::Seek(position) => {
+                if let Some(h) = self.active_handle() {
+                    h.seek_to(position);
+                }
+            }
+            Command::Next => {
+                // skipping moves on, even if the sound is repeated
+                let skip_mode = match self.repeat {
+                    RepeatMode::One => RepeatMode::All,
+                    mode => mode,
+                };
+                match self.next_index(skip_mode) {
+                    Some(i) => self.play_sound(&self.queue[i].clone(), manager),
+                    None => {
+                        if let Some(s) = &mut self.active_sound {
+                            s.stop();
+                            self.resume.forget(s);
+                            self.events.push(Event::EndOfQueue);
+                        }
+                    }
+                }
+            }
+            Command::Prev => {
+                let position = self.active_handle().map(|h| h.position());
+                let prev = self
+                    .prev_index()
+                    .filter(|_| position.unwrap_or_default() < RESTART_AFTER);
+                match prev {
+                    Some(i) => self.play_sound(&self.queue[i].clone(), manager),
+                    None => self.restart(manager),
+                }
+            }
+            Command::PlaySound(sound) => self.play_sound(&sound, manager),
+            Command::Enqueue(sound) => {
+                if !self.queue.contains(&sound) {
+                    self.queue.push(sound);
+                    self.sync_shuffle();
+                }
+            }
+            Command::Bookmark => self.bookmark(),
+            Command::PlayBookmark(sound, position) => {
+                if self.active_sound.as_ref() != Some(&sound) {
+                    if let Some(active) = &mut self.active_sound {
+                        self.resume.remember(active);
+                        active.stop();
+                    }
+                    let mut sound = self.with_settings(&sound);
+                    let _ = sound.play_load_mut(manager);
+                    self.set_active(sound);
+                }
+                if let Some(h) = self.active_handle() {
+                    h.seek_to(position);
+                }
+            }
+            Command::Favourite => {
+                if let Some(s) = &self.active_sound {
+                    self.favourites.insert(s.without_stream());
+                }
+            }
+            Command::Remove(i) => {
+                if i < self.queue.len() {
+                    self.queue.remove(i);
+                    self.sync_shuffle();
+                }
+            }
+            Command::Move { from, to } => {
+                if from < self.queue.len() && to < self.queue.len() {
+                    let sound = self.queue.remove(from);
+                    self.queue.insert(to, sound);
+                }
+            }
+            Command::SetRepeat(repeat) => self.repeat = repeat,
+            Command::SetShuffle(shuffle) => {
+                // without shuffle, playback just continues in playlist order
+                self.shuffle = if shuffle {
+                    Some(ShuffleOrder::new(&self.queue, self.active_sound.as_ref()))
+                } else {
+                    None
+                };
+            }
+            Command::SetSpeed(speed) => self.change_active(|s| s.set_speed(speed)),
+            Command::SetSkipSilence(skip) => self.change_active(|s| s.set_skip_silence(skip)),
+            Command::MarkLoopStart => self.change_active(|s| {
+                if let Some(position) = s.streamhandle.as_ref().map(|h| h.position()) {
+                    s.set_loop_start(position);
+                }
+            }),
+            Command::MarkLoopEnd => self.change_active(|s| {
+                if let Some(position) = s.streamhandle.as_ref().map(|h| h.position()) {
+                    s.set_loop_end(position);
+                }
+            }),
+            Command::ClearLoop => self.change_active(|s| s.clear_loop()),
+            Command::SetSleepTimer(timer) => {
+                if let Some(old) = std::mem::replace(&mut self.sleep, timer) {
+                    old.cancel(self.active_sound.as_mut());
+                }
+            }
+            Command::SetGainMode(mode) => {
+                manager.set_gain_mode(mode);
+                for s in self
+                    .active_sound
+                    .iter_mut()
+                    .chain(self.preloaded.iter_mut())
+                {
+                    let factor = s.replaygain.factor(mode);
+                    if let Some(h) = s.streamhandle.as_mut() {
+                        h.set_replaygain(factor);
+                    }
+                }
+            }
+            Command::SetReplayGain(path, gain) => {
+                let sounds = self
+                    .queue
+                    .iter_mut()
+                    .chain(self.active_sound.iter_mut())
+                    .chain(self.preloaded.iter_mut());
+                for sound in sounds.filter(|s| s.path == path) {
+                    sound.replaygain = gain;
+                    if let Some(h) = sound.streamhandle.as_mut() {
+                        h.set_replaygain(gain.factor(manager.gain_mode()));
+                    }
+                }
+            }
+            Command::SetPreservePitch(preserve_pitch) => {
+                manager.set_preserve_pitch(preserve_pitch);
+                if let Some(h) = self.active_handle() {
+                    h.set_preserve_pitch(preserve_pitch);
+                }
+            }
+            Command::SetCrossfade(crossfade) => self.crossfade = crossfade,
+            Command::SetResume(settings) => self.resume.set_settings(settings),
+        }
+    }
+
+    /// Move playback along: load what comes next, and move on once the active sound ends.
+    /// Call this regularly, e.g. on every frame.
+    pub fn update(&mut self, manager: &mut StreamManager) {
+        if let Some(timer) = &mut self.sleep {
+            let running = match &mut self.active_sound {
+                Some(s) => timer.update(s),
+                None => false,
+            };
+            if !running {
+                info!("Sleep timer ran out");
+                self.sleep = None;
+            }
+        }
+        self.preload_next(manager);
+        self.crossfade_next();
+        self.advance(manager);
+
+        let position = self.active_handle().map(|h| h.position());
+        if position.is_some() && position != self.position {
+            self.events.extend(position.map(Event::PositionChanged));
+        }
+        self.position = position;
+    }
+
+    /// Everything that happened since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn active_sound(&self) -> Option<&MetaSound> {
+        self.active_sound.as_ref()
+    }
+
+    /// The playlist
+    pub fn queue(&self) -> &SoundQueue {
+        &self.queue
+    }
+
+    /// Every sound that is on the playlist, active or loaded ahead
+    pub fn sounds(&self) -> impl Iterator<Item = &MetaSound> {
+        self.queue
+            .iter()
+            .chain(self.active_sound.iter())
+            .chain(self.preloaded.iter())
+    }
+
+    /// How often each sound was played
+    pub fn play_count(&self) -> &HashMap<MetaSound, usize> {
+        &self.play_count
+    }
+
+    pub fn favourites(&self) -> &HashSet<MetaSound> {
+        &self.favourites
+    }
+
+    pub fn bookmarks(&self) -> &HashSet<MetaSound> {
+        &self.bookmarks
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn shuffled(&self) -> bool {
+        self.shuffle.is_some()
+    }
+
+    pub fn crossfade(&self) -> f64 {
+        self.crossfade
+    }
+
+    pub fn resume_settings(&self) -> ResumeSettings {
+        self.resume.settings()
+    }
+
+    pub fn sleep_timer(&self) -> Option<&SleepTimer> {
+        self.sleep.as_ref()
+    }
+
+    fn active_handle(&mut self) -> Option<&mut StreamHandle> {
+        self.active_sound
+            .as_mut()
+            .and_then(|s| s.streamhandle.as_mut())
+    }
+
+    /// Keep the position of the active sound, e.g. before quitting
+    pub fn remember_position(&mut self) {
+        if let Some(s) = &self.active_sound {
+            self.resume.remember(s);
+        }
+    }
+
+    /// Follow changes to the playlist in the shuffle order
+    fn sync_shuffle(&mut self) {
+        if let Some(order) = &mut self.shuffle {
+            order.sync(&self.queue, self.active_sound.as_ref());
+        }
+    }
+
+    /// Change a setting of the active sound, and keep it for the next time it is played
+    fn change_active(&mut self, change: impl FnOnce(&mut MetaSound)) {
+        if let Some(s) = &mut self.active_sound {
+            change(s);
+            self.settings.insert(s.path.clone(), s.settings());
+        }
+    }
+
+    /// The sound with the settings it was last played with
+    fn with_settings(&self, sound: &MetaSound) -> MetaSound {
+        with_settings(&self.settings, sound)
+    }
+
+    fn set_active(&mut self, sound: MetaSound) {
+        self.events.push(Event::TrackChanged(sound.clone()));
+        self.active_sound = Some(sound);
+        self.finished = false;
+    }
+
+    fn play(&mut self, manager: &mut StreamManager) {
+        let sound = match &mut self.active_sound {
+            Some(sound) => sound,
+            None => {
+                if let Some(first) = self.queue.first().cloned() {
+                    self.play_sound(&first, manager);
+                }
+                return;
+            }
+        };
+        match sound.streamhandle.as_mut() {
+            Some(h) if h.state() == StreamState::Playing => {}
+            Some(h) if h.state() == StreamState::Paused => {
+                let away = h.paused_for().unwrap_or_default();
+                let rewind = self.resume.rewind_for(away);
+                if rewind > 0.0 {
+                    h.seek_to((h.position() - rewind).max(0.0));
+                }
+                h.resume();
+            }
+            handle => {
+                let unplayed = handle.is_none();
+                *sound = sound.load_streamhandle(manager);
+                self.resume.resume(sound);
+                // not inside the log macro, which skips its arguments when logging is off
+                let played = sound.play();
+                info!("{:?}", played);
+                if unplayed {
+                    *self.play_count.entry(sound.without_stream()).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    fn count_play(&mut self, sound: &MetaSound) {
+        *self.play_count.entry(sound.without_stream()).or_insert(0) += 1;
+    }
+
+    /// Play the active sound from its start
+    fn restart(&mut self, manager: &mut StreamManager) {
+        let sound = match &mut self.active_sound {
+            Some(sound) => sound,
+            None => return,
+        };
+        self.resume.forget(sound);
+        match sound.streamhandle.as_ref().map(|h| h.state()) {
+            Some(StreamState::Playing) | Some(StreamState::Paused) => {
+                if let Some(h) = self.active_handle() {
+                    h.seek_to(0.0);
+                }
+            }
+            // loads it again
+            _ => self.play(manager),
+        }
+    }
+
+    /// Stop the active sound and play `sound` instead
+    fn play_sound(&mut self, sound: &MetaSound, manager: &mut StreamManager) {
+        if let Some(s) = &mut self.active_sound {
+            self.resume.remember(s);
+            s.stop();
+        }
+        let mut next = self.with_settings(sound).load_streamhandle(manager);
+        self.resume.resume(&mut next);
+        let _ = next.play();
+        self.set_active(next);
+        self.count_play(sound);
+    }
+
+    fn bookmark(&mut self) {
+        let s = match &mut self.active_sound {
+            Some(s) => s,
+            None => return,
+        };
+        if let Some(streamhandle) = &s.streamhandle {
+            s.bookmarks.push(streamhandle.position());
+            let mut prev_bookmarks = self
+                .bookmarks
+                .get(s)
+                .map(|b| b.bookmarks.clone())
+                .unwrap_or_default();
+            prev_bookmarks.extend(s.bookmarks.clone());
+            prev_bookmarks.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            prev_bookmarks.dedup();
+            debug!("{:?}", prev_bookmarks);
+            s.bookmarks = prev_bookmarks;
+            self.bookmarks.replace(s.without_stream());
+        }
+    }
+
+    /// Playlist index of the sound after the active one
+    fn next_index(&self, repeat: RepeatMode) -> Option<usize> {
+        let active = self.active_sound.as_ref()?;
+        match &self.shuffle {
+            Some(order) => order.next_index(&self.queue, active, repeat),
+            None => self.queue.next_index(active, repeat),
+        }
+    }
+
+    /// Playlist index of the sound before the active one
+    fn prev_index(&self) -> Option<usize> {
+        let active = self.active_sound.as_ref()?;
+        match &self.shuffle {
+            Some(order) => order.prev_index(&self.queue, active, self.repeat),
+            None => self.queue.prev_index(active, self.repeat),
+        }
+    }
+
+    /// Move on to the next sound once the active one is done
+    fn advance(&mut self, manager: &mut StreamManager) {
+        let state = self
+            .active_sound
+            .as_ref()
+            .and_then(|s| s.streamhandle.as_ref())
+            .map(|h| h.state());
+        if state != Some(StreamState::Finished) {
+            self.finished = false;
+            return;
+        }
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if let Some(s) = &self.active_sound {
+            self.events.push(Event::Finished(s.clone()));
+        }
+
+        if let Some(i) = self.next_index(self.repeat) {
+            info!("Sound has finished playing, next one!");
+            match self.preloaded.take().filter(|p| p == &self.queue[i]) {
+                // Usually already started by the audio thread
+                Some(mut next) => {
+                    if let Some(s) = &self.active_sound {
+                        self.resume.forget(s);
+                    }
+                    let _ = next.play();
+                    self.count_play(&next);
+                    self.set_active(next);
+                }
+                None => self.play_sound(&self.queue[i].clone(), manager),
+            }
+        } else {
+            self.events.push(Event::EndOfQueue);
+        }
+    }
+
+    /// Load the sound after the active one ahead of time. Without crossfade it is handed to
+    /// the active stream, which starts it without a gap.
+    fn preload_next(&mut self, manager: &mut StreamManager) {
+        let queue = &self.queue;
+        let next = self.next_index(self.repeat).map(|i| &queue[i]);
+        let active = match &mut self.active_sound {
+            Some(active) => active,
+            None => return,
+        };
+        // a repeated sound starts over
+        let repeated = next == Some(&*active);
+        let streamhandle = match active.streamhandle.as_mut() {
+            Some(h) if h.state() == StreamState::Playing => h,
+            _ => return,
+        };
+
+        if self.preloaded.as_ref() != next {
+            let (resume, settings) = (&self.resume, &self.settings);
+            self.preloaded = next.map(|next| {
+                debug!("Preloading {}", next.name);
+                let mut next = with_settings(settings, next).load_streamhandle(manager);
+                if !repeated {
+                    resume.resume(&mut next);
+                }
+                next
+            });
+            streamhandle.set_follower(None);
+        }
+
+        // With crossfade, the next sound is started by `crossfade_next` instead
+        let crossfade = self.crossfade;
+        let follower = self
+            .preloaded
+            .as_ref()
+            .and_then(|p| p.streamhandle.as_ref())
+            .filter(|_| crossfade <= 0.0);
+        if follower.is_some() != streamhandle.has_follower() {
+            streamhandle.set_follower(follower);
+        }
+    }
+
+    /// Once the active sound is about to end, fade it out and the preloaded one in.
+    /// The outgoing stream keeps playing until it is done, even though its handle is dropped.
+    fn crossfade_next(&mut self) {
+        if self.crossfade <= 0.0 || self.preloaded.is_none() {
+            return;
+        }
+        let active = self.active_sound.as_mut();
+        let streamhandle = match active.and_then(|s| s.streamhandle.as_mut()) {
+            Some(h) if h.state() == StreamState::Playing && h.duration() > 0.0 => h,
+            _ => return,
+        };
+        // short sounds would otherwise be skipped right away
+        let crossfade = self.crossfade.min(streamhandle.duration() / 2.0);
+        // fades run in real time, which is not sound time when sped up
+        let remaining = (streamhandle.duration() - streamhandle.position()) / streamhandle.speed();
+        if remaining > crossfade {
+            return;
+        }
+
+        if let Some(mut next) = self.preloaded.take() {
+            info!("Crossfading to {}", next.name);
+            streamhandle.set_volume(0.0, remaining);
+            if let Some(next_handle) = next.streamhandle.as_mut() {
+                next_handle.set_volume(0.0, 0.0);
+                next_handle.set_volume(1.0, crossfade);
+            }
+            let _ = next.play();
+            self.count_play(&next);
+            if let Some(old) = &self.active_sound {
+                self.resume.forget(old);
+            }
+            self.set_active(next);
+        }
+    }
+}
+
+fn with_settings(settings: &HashMap<PathBuf, SoundSettings>, sound: &MetaSound) -> MetaSound {
+    match settings.get(&sound.path) {
+        Some(settings) => sound.with_settings(*settings),
+        None => sound.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{offline_manager, play_for, tone_sound};
+    use std::path::Path;
+
+    fn player(dir: &Path, lengths: &[f64]) -> Player {
+        let queue = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, secs)| tone_sound(dir, &format!("{}.wav", i), *secs))
+            .collect();
+        Player {
+            queue,
+            ..Player::default()
+        }
+    }
+
+    fn state(sound: &Option<MetaSound>) -> Option<StreamState> {
+        sound.as_ref()?.streamhandle.as_ref().map(|h| h.state())
+    }
+
+    /// Play for a while, updating the player as the UI would
+    fn run(player: &mut Player, manager: &mut StreamManager, secs: f64) {
+        for _ in 0..(secs / 0.1).round() as usize {
+            play_for(manager, 0.1);
+            player.update(manager);
+        }
+    }
+
+    fn changes(events: &[Event]) -> Vec<Event> {
+        events
+            .iter()
+            .filter(|e| !matches!(e, Event::PositionChanged(_)))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn play_sound_switches_sounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[2.0, 2.0]);
+        let (a, b) = (player.queue[0].clone(), player.queue[1].clone());
+
+        player.command(Command::PlaySound(a.clone()), &mut manager);
+        play_for(&mut manager, 0.5);
+        let first = player.active_sound.clone().unwrap();
+        assert_eq!(first, a);
+        assert!(first.duration.as_secs_f64() > 1.9);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+
+        player.command(Command::PlaySound(b.clone()), &mut manager);
+        play_for(&mut manager, 0.5);
+        assert_eq!(player.active_sound.as_ref(), Some(&b));
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+        assert!(player.active_handle().unwrap().position() > 0.3);
+        assert_eq!(first.streamhandle.unwrap().state(), StreamState::Stopped);
+
+        player.command(Command::PlaySound(a.clone()), &mut manager);
+        assert_eq!(player.play_count[&a], 2);
+        assert_eq!(player.play_count[&b], 1);
+        assert_eq!(
+            player.take_events(),
+            vec![
+                Event::TrackChanged(a.clone()),
+                Event::TrackChanged(b),
+                Event::TrackChanged(a)
+            ]
+        );
+    }
+
+    #[test]
+    fn play_starts_the_playlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[2.0, 2.0]);
+        player.command(Command::Play, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[0]));
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+    }
+
+    #[test]
+    fn pause_and_play() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[3.0]);
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 0.5);
+        player.command(Command::Pause, &mut manager);
+        run(&mut player, &mut manager, 0.3);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Paused));
+        player.take_events();
+        run(&mut player, &mut manager, 0.5);
+        assert!(player.take_events().is_empty());
+
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 0.5);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+        assert!(matches!(
+            player.take_events().last(),
+            Some(Event::PositionChanged(_))
+        ));
+    }
+
+    #[test]
+    fn seek_reports_the_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[3.0]);
+        player.command(Command::Play, &mut manager);
+        player.command(Command::Seek(2.0), &mut manager);
+        run(&mut player, &mut manager, 0.2);
+        let position = player.take_events().into_iter().find_map(|e| match e {
+            Event::PositionChanged(p) => Some(p),
+            _ => None,
+        });
+        assert!(position.unwrap() >= 2.0);
+    }
+
+    #[test]
+    fn stop_remembers_the_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[3.0]);
+        player.resume.min_duration = 0.0;
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 1.0);
+        player.command(Command::Stop, &mut manager);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Stopped));
+
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 0.1);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+        assert!(player.active_handle().unwrap().position() > 0.8);
+    }
+
+    #[test]
+    fn next_and_prev() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[2.0, 2.0, 2.0]);
+        player.command(Command::Play, &mut manager);
+        player.command(Command::Next, &mut manager);
+        player.command(Command::Next, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[2]));
+        player.command(Command::Prev, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[1]));
+
+        // skipping moves on, even if the sound is repeated
+        player.command(Command::SetRepeat(RepeatMode::One), &mut manager);
+        player.command(Command::Next, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[2]));
+    }
+
+    #[test]
+    fn prev_restarts_a_sound_that_played_a_while() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[5.0, 5.0]);
+        player.command(Command::PlaySound(player.queue[1].clone()), &mut manager);
+        // seeks right away while paused
+        player.command(Command::Pause, &mut manager);
+        player.command(Command::Seek(RESTART_AFTER + 1.0), &mut manager);
+        player.command(Command::Prev, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[1]));
+        assert_eq!(player.active_handle().unwrap().position(), 0.0);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Paused));
+
+        // and goes back when pressed again
+        player.command(Command::Prev, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[0]));
+    }
+
+    #[test]
+    fn prev_on_the_first_sound_restarts_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[2.0, 2.0]);
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 1.0);
+        player.take_events();
+        player.command(Command::Prev, &mut manager);
+        run(&mut player, &mut manager, 0.2);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[0]));
+        assert!(player.active_handle().unwrap().position() < 0.5);
+        assert!(changes(&player.take_events()).is_empty());
+
+        // unless the playlist repeats
+        player.command(Command::SetRepeat(RepeatMode::All), &mut manager);
+        player.command(Command::Prev, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[1]));
+    }
+
+    #[test]
+    fn prev_plays_a_finished_sound_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[0.5]);
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 1.0);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Finished));
+        player.command(Command::Prev, &mut manager);
+        run(&mut player, &mut manager, 0.2);
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+    }
+
+    #[test]
+    fn stops_after_the_last_sound() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[0.5, 0.5]);
+        let (a, b) = (player.queue[0].clone(), player.queue[1].clone());
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 2.0);
+        assert_eq!(player.active_sound.as_ref(), Some(&b));
+        assert_eq!(state(&player.active_sound), Some(StreamState::Finished));
+        assert_eq!(
+            changes(&player.take_events()),
+            vec![
+                Event::TrackChanged(a.clone()),
+                Event::Finished(a),
+                Event::TrackChanged(b.clone()),
+                Event::Finished(b.clone()),
+                Event::EndOfQueue
+            ]
+        );
+        // the last sound is not played over and over
+        assert_eq!(player.play_count[&b], 1);
+    }
+
+    #[test]
+    fn wraps_around_when_the_playlist_repeats() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[0.5, 0.5]);
+        player.repeat = RepeatMode::All;
+        player.command(Command::PlaySound(player.queue[1].clone()), &mut manager);
+        run(&mut player, &mut manager, 0.8);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[0]));
+        assert!(!player.take_events().contains(&Event::EndOfQueue));
+    }
+
+    #[test]
+    fn next_after_the_last_sound_stops() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[2.0, 2.0]);
+        player.command(Command::PlaySound(player.queue[1].clone()), &mut manager);
+        player.take_events();
+        player.command(Command::Next, &mut manager);
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[1]));
+        assert_eq!(state(&player.active_sound), Some(StreamState::Stopped));
+        assert_eq!(player.take_events(), vec![Event::EndOfQueue]);
+    }
+
+    #[test]
+    fn plays_through_the_playlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[0.5, 2.0]);
+        let (a, b) = (player.queue[0].clone(), player.queue[1].clone());
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 0.2);
+        // started by the audio thread, without waiting for the player
+        assert_eq!(player.preloaded.as_ref(), Some(&b));
+        play_for(&mut manager, 0.5);
+        assert_eq!(state(&player.preloaded), Some(StreamState::Playing));
+
+        run(&mut player, &mut manager, 0.1);
+        assert_eq!(player.active_sound.as_ref(), Some(&b));
+        assert_eq!(
+            changes(&player.take_events()),
+            vec![
+                Event::TrackChanged(a.clone()),
+                Event::Finished(a),
+                Event::TrackChanged(b.clone())
+            ]
+        );
+        assert_eq!(player.play_count[&b], 1);
+        // copies of the sounds played must not keep their streams open
+        assert!(player.play_count.keys().all(|s| s.streamhandle.is_none()));
+    }
+
+    #[test]
+    fn nothing_is_preloaded_after_the_last_sound() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[1.0]);
+        player.command(Command::Play, &mut manager);
+        player.update(&mut manager);
+        assert!(player.preloaded.is_none());
+
+        player.command(Command::SetRepeat(RepeatMode::One), &mut manager);
+        player.update(&mut manager);
+        assert_eq!(player.preloaded.as_ref(), Some(&player.queue[0]));
+    }
+
+    #[test]
+    fn crossfades_into_the_next_sound() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[1.0, 2.0]);
+        player.crossfade = 0.5;
+        player.command(Command::Play, &mut manager);
+        run(&mut player, &mut manager, 0.7);
+        // the next sound takes over before the first one ends
+        assert_eq!(player.active_sound.as_ref(), Some(&player.queue[1]));
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+    }
+
+    fn bookmark_at(player: &mut Player, manager: &mut StreamManager, position: f64) {
+        player.command(Command::Seek(position), manager);
+        player.command(Command::Bookmark, manager);
+    }
+
+    fn bookmarks(player: &Player, sound: &MetaSound) -> Vec<f64> {
+        player.bookmarks.get(sound).unwrap().bookmarks.clone()
+    }
+
+    #[test]
+    fn bookmarks_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[3.0, 3.0]);
+        let (a, b) = (player.queue[0].clone(), player.queue[1].clone());
+        player.command(Command::Bookmark, &mut manager);
+        assert!(player.bookmarks.is_empty());
+
+        player.command(Command::PlaySound(a.clone()), &mut manager);
+        // seeks right away while paused
+        player.command(Command::Pause, &mut manager);
+        bookmark_at(&mut player, &mut manager, 2.0);
+        bookmark_at(&mut player, &mut manager, 1.0);
+        bookmark_at(&mut player, &mut manager, 2.0);
+        assert_eq!(bookmarks(&player, &a), vec![1.0, 2.0]);
+        assert_eq!(
+            player.active_sound.as_ref().unwrap().bookmarks,
+            vec![1.0, 2.0]
+        );
+
+        // the playlist entry has none of them, they are merged with the ones kept
+        player.command(Command::PlaySound(b.clone()), &mut manager);
+        player.command(Command::PlaySound(a.clone()), &mut manager);
+        player.command(Command::Pause, &mut manager);
+        bookmark_at(&mut player, &mut manager, 0.5);
+        bookmark_at(&mut player, &mut manager, 1.0);
+        assert_eq!(bookmarks(&player, &a), vec![0.5, 1.0, 2.0]);
+        assert_eq!(player.bookmarks.len(), 1);
+        assert!(player.bookmarks.iter().all(|s| s.streamhandle.is_none()));
+    }
+
+    #[test]
+    fn play_bookmark() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[3.0, 3.0]);
+        let (a, b) = (player.queue[0].clone(), player.queue[1].clone());
+        player.command(Command::PlaySound(a.clone()), &mut manager);
+        player.command(Command::PlayBookmark(b.clone(), 2.0), &mut manager);
+        run(&mut player, &mut manager, 0.3);
+        assert_eq!(player.active_sound.as_ref(), Some(&b));
+        let position = player.active_handle().unwrap().position();
+        assert!(position > 2.0 && position < 2.5);
+
+        player.command(Command::PlayBookmark(b, 1.0), &mut manager);
+        run(&mut player, &mut manager, 0.3);
+        let position = player.active_handle().unwrap().position();
+        assert!(position > 1.0 && position < 1.5);
+    }
+
+    #[test]
+    fn enqueue_skips_sounds_on_the_playlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[1.0]);
+        player.command(Command::Enqueue(player.queue[0].clone()), &mut manager);
+        assert_eq!(player.queue.len(), 1);
+        let other = tone_sound(dir.path(), "other.wav", 1.0);
+        player.command(Command::Enqueue(other.clone()), &mut manager);
+        assert_eq!(player.queue, vec![player.queue[0].clone(), other]);
+    }
+
+    #[test]
+    fn remove_and_move_sounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[1.0, 1.0, 1.0]);
+        let (a, b, c) = (
+            player.queue[0].clone(),
+            player.queue[1].clone(),
+            player.queue[2].clone(),
+        );
+        player.command(Command::Move { from: 0, to: 2 }, &mut manager);
+        assert_eq!(player.queue, vec![b.clone(), c.clone(), a.clone()]);
+        player.command(Command::Remove(1), &mut manager);
+        assert_eq!(player.queue, vec![b.clone(), a.clone()]);
+        // out of range
+        player.command(Command::Remove(2), &mut manager);
+        player.command(Command::Move { from: 0, to: 2 }, &mut manager);
+        assert_eq!(player.queue, vec![b, a]);
+    }
+
+    fn settings(sound: Option<&MetaSound>) -> (f64, bool, bool, f64, f64) {
+        let s = sound.unwrap();
+        (s.speed, s.skip_silence, s.looped, s.loop_start, s.loop_end)
+    }
+
+    #[test]
+    fn settings_of_a_sound_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[3.0, 3.0]);
+        let (a, b) = (player.queue[0].clone(), player.queue[1].clone());
+        player.command(Command::SetSpeed(1.5), &mut manager);
+        assert!(player.settings.is_empty());
+
+        player.command(Command::PlaySound(a.clone()), &mut manager);
+        player.command(Command::SetSpeed(1.5), &mut manager);
+        player.command(Command::SetSkipSilence(true), &mut manager);
+        player.command(Command::Pause, &mut manager);
+        player.command(Command::Seek(0.5), &mut manager);
+        player.command(Command::MarkLoopStart, &mut manager);
+        player.command(Command::Seek(2.0), &mut manager);
+        player.command(Command::MarkLoopEnd, &mut manager);
+        let changed = (1.5, true, true, 0.5, 2.0);
+        assert_eq!(settings(player.active_sound()), changed);
+
+        // copies kept elsewhere, like the favourites, get them too
+        player.command(Command::PlaySound(b.clone()), &mut manager);
+        assert_eq!(player.active_sound().unwrap().speed, 1.0);
+        player.command(Command::PlaySound(a.clone()), &mut manager);
+        assert_eq!(settings(player.active_sound()), changed);
+        player.command(Command::PlayBookmark(b, 1.0), &mut manager);
+        player.command(Command::PlayBookmark(a.clone(), 1.0), &mut manager);
+        assert_eq!(settings(player.active_sound()), changed);
+        player.command(Command::Next, &mut manager);
+        player.command(Command::Prev, &mut manager);
+        assert_eq!(settings(player.active_sound()), changed);
+
+        player.command(Command::ClearLoop, &mut manager);
+        player.command(Command::PlaySound(a), &mut manager);
+        assert_eq!(
+            settings(player.active_sound()),
+            (1.5, true, false, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn favourite_the_active_sound() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[1.0]);
+        player.command(Command::Favourite, &mut manager);
+        assert!(player.favourites().is_empty());
+        player.command(Command::Play, &mut manager);
+        player.command(Command::Favourite, &mut manager);
+        assert!(player.favourites().contains(&player.queue[0]));
+        assert!(player.favourites.iter().all(|s| s.streamhandle.is_none()));
+    }
+
+    #[test]
+    fn shuffle_follows_the_playlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[1.0, 1.0]);
+        player.command(Command::Play, &mut manager);
+        player.command(Command::SetShuffle(true), &mut manager);
+        player.command(Command::Remove(1), &mut manager);
+        assert_eq!(player.next_index(RepeatMode::Off), None);
+        let other = tone_sound(dir.path(), "other.wav", 1.0);
+        player.command(Command::Enqueue(other), &mut manager);
+        assert_eq!(player.next_index(RepeatMode::Off), Some(1));
+    }
+
+    #[test]
+    fn sleep_timers_of_a_sound_end_with_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut player = player(dir.path(), &[3.0, 3.0]);
+        let b = player.queue[1].clone();
+        player.command(Command::Play, &mut manager);
+        let timer = SleepTimer::end_of_track(player.active_sound().unwrap());
+        player.command(Command::SetSleepTimer(timer), &mut manager);
+        run(&mut player, &mut manager, 0.2);
+        assert!(player.sleep_timer().is_some());
+
+        player.command(Command::Next, &mut manager);
+        run(&mut player, &mut manager, 0.5);
+        assert_eq!(player.active_sound(), Some(&b));
+        assert_eq!(state(&player.active_sound), Some(StreamState::Playing));
+        assert!(player.sleep_timer().is_none());
+    }
+}
@@ -0,0 +1,148 @@
+//! Finding silence in decoded audio, so long pauses in spoken word can be shortened
+
+use kira::Frame;
+
+/// Frames below this level in dBFS on both channels count as silent
+pub const THRESHOLD: f64 = -45.0;
+
+/// Silences are cut down to this many seconds, enough to still hear a pause
+pub const MIN_SILENCE: f64 = 0.5;
+
+#[derive(Debug, Clone)]
+pub struct SilenceDetector {
+    /// Linear level below which a frame is silent
+    threshold: f32,
+    /// Silent frames to keep before dropping the rest
+    min_frames: usize,
+    /// Silent frames in a row so far
+    run: usize,
+}
+
+impl SilenceDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        Self::with_settings(sample_rate, THRESHOLD, MIN_SILENCE)
+    }
+
+    pub fn with_settings(sample_rate: u32, threshold_db: f64, min_silence: f64) -> Self {
+        Self {
+            threshold: 10f64.powf(threshold_db / 20.0) as f32,
+            min_frames: (min_silence * sample_rate as f64).round() as usize,
+            run: 0,
+        }
+    }
+
+    /// Whether `frame` should be played. Silent frames beyond the first `min_silence`
+    /// seconds of a silent stretch are not.
+    pub fn keep(&mut self, frame: Frame) -> bool {
+        if frame.left.abs() < self.threshold && frame.right.abs() < self.threshold {
+            self.run += 1;
+            self.run <= self.min_frames
+        } else {
+            self.run = 0;
+            true
+        }
+    }
+
+    /// Forget the current stretch, e.g. after a seek
+    pub fn reset(&mut self) {
+        self.run = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    fn tone(secs: f64) -> Vec<Frame> {
+        (0..(secs * SAMPLE_RATE as f64) as usize)
+            .map(|i| {
+                Frame::from_mono(0.5 * (2.0 * PI * 440.0 * i as f32 / SAMPLE_RATE as f32).cos())
+            })
+            .collect()
+    }
+
+    fn silence(secs: f64, level: f32) -> Vec<Frame> {
+        (0..(secs * SAMPLE_RATE as f64) as usize)
+            .map(|i| Frame::from_mono(if i % 2 == 0 { level } else { -level }))
+            .collect()
+    }
+
+    fn kept(detector: &mut SilenceDetector, frames: &[Frame]) -> usize {
+        frames.iter().filter(|f| detector.keep(**f)).count()
+    }
+
+    fn frames(secs: f64) -> usize {
+        (secs * SAMPLE_RATE as f64) as usize
+    }
+
+    #[test]
+    fn sound_is_kept() {
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        let input = tone(2.0);
+        // samples near zero crossings are quiet, but far from a silence
+        assert_eq!(kept(&mut detector, &input), input.len());
+    }
+
+    #[test]
+    fn short_silence_is_kept() {
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        let input = [tone(0.5), silence(0.3, 0.0), tone(0.5)].concat();
+        assert_eq!(kept(&mut detector, &input), input.len());
+    }
+
+    #[test]
+    fn long_silence_is_shortened() {
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        let input = [tone(0.5), silence(3.0, 0.0), tone(0.5)].concat();
+        assert_eq!(
+            kept(&mut detector, &input),
+            frames(0.5) + frames(MIN_SILENCE) + frames(0.5)
+        );
+    }
+
+    #[test]
+    fn noise_floor_counts_as_silence() {
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        // -60 dBFS hiss
+        let input = silence(2.0, 0.001);
+        assert_eq!(kept(&mut detector, &input), frames(MIN_SILENCE));
+
+        // but a quiet passage at -30 dBFS does not
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        let input = silence(2.0, 0.03);
+        assert_eq!(kept(&mut detector, &input), input.len());
+    }
+
+    #[test]
+    fn every_silence_is_shortened_on_its_own() {
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        let input = [silence(1.0, 0.0), tone(0.2), silence(2.0, 0.0), tone(0.2)].concat();
+        assert_eq!(
+            kept(&mut detector, &input),
+            2 * frames(MIN_SILENCE) + 2 * frames(0.2)
+        );
+    }
+
+    #[test]
+    fn sound_on_one_channel_is_kept() {
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        let input = tone(1.0)
+            .into_iter()
+            .map(|f| Frame::new(0.0, f.right))
+            .collect::<Vec<_>>();
+        assert_eq!(kept(&mut detector, &input), input.len());
+    }
+
+    #[test]
+    fn reset_starts_a_new_stretch() {
+        let mut detector = SilenceDetector::with_settings(SAMPLE_RATE, THRESHOLD, 1.0);
+        let input = silence(0.8, 0.0);
+        assert_eq!(kept(&mut detector, &input), input.len());
+        detector.reset();
+        assert_eq!(kept(&mut detector, &input), input.len());
+        assert_eq!(kept(&mut detector, &input), frames(0.2));
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::sound::MetaSound;
+use crate::stream::StreamState;
+
+/// Seconds over which the sound fades out before the timer runs out
+const FADE_OUT: f64 = 30.0;
+/// Longer than any pause fade, after which the volume can be restored silently
+const RESTORE_AFTER: Duration = Duration::from_secs(3);
+
+/// Pauses playback after a while, fading out before
+#[derive(Debug, Clone, PartialEq)]
+pub struct SleepTimer {
+    target: SleepTarget,
+    fading: bool,
+    /// Paused, waiting to restore the volume
+    asleep: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SleepTarget {
+    At(Instant),
+    /// A position within a sound, like its end
+    Position {
+        path: PathBuf,
+        position: f64,
+    },
+}
+
+impl SleepTimer {
+    pub fn after(duration: Duration) -> Self {
+        Self {
+            target: SleepTarget::At(Instant::now() + duration),
+            fading: false,
+            asleep: false,
+        }
+    }
+
+    pub fn end_of_track(sound: &MetaSound) -> Option<Self> {
+        let duration = sound.streamhandle.as_ref()?.duration();
+        Some(Self::at_position(sound, duration))
+    }
+
+    /// Sleep at the next chapter or bookmark, or at the end if there is none.
+    pub fn end_of_chapter(sound: &MetaSound) -> Option<Self> {
+        let handle = sound.streamhandle.as_ref()?;
+        let position = handle.position();
+        let end = handle
+            .chapters
+            .iter()
+            .chain(&sound.bookmarks)
+            .filter(|t| **t > position)
+            .fold(handle.duration(), |end, t| end.min(*t));
+        Some(Self::at_position(sound, end))
+    }
+
+    fn at_position(sound: &MetaSound, position: f64) -> Self {
+        Self {
+            target: SleepTarget::Position {
+                path: sound.path.clone(),
+                position,
+            },
+            fading: false,
+            asleep: false,
+        }
+    }
+
+    /// Seconds until playback pauses. A timer set within a sound ends once another one plays.
+    pub fn remaining(&self, active: &MetaSound) -> f64 {
+        match &self.target {
+            SleepTarget::At(deadline) => deadline
+                .checked_duration_since(Instant::now())
+                .unwrap_or_default()
+                .as_secs_f64(),
+            SleepTarget::Position { path, position } => match &active.streamhandle {
+                Some(h) if *path == active.path => (position - h.position()) / h.speed(),
+                _ => 0.0,
+            },
+        }
+    }
+
+    /// Fade out the active sound and pause it once the time is up.
+    /// Returns false when the timer is done.
+    pub fn update(&mut self, active: &mut MetaSound) -> bool {
+        if let SleepTarget::Position { path, .. } = &self.target {
+            // moved on to another sound, which has nothing to do with the position
+            if *path != active.path {
+                return false;
+            }
+        }
+        let remaining = self.remaining(active);
+        let handle = match active.streamhandle.as_mut() {
+            Some(h) => h,
+            None => return remaining > 0.0,
+        };
+
+        if self.asleep {
+            // the next ▶ should be audible again, once the pause has faded out
+            let fading_out = handle.paused_for().is_some_and(|p| p < RESTORE_AFTER);
+            if !fading_out {
+                handle.set_volume(1.0, 0.0);
+            }
+            return fading_out;
+        }
+        // a paused sound stays paused, only a running clock keeps counting
+        if handle.state() != StreamState::Playing {
+            return remaining > 0.0;
+        }
+
+        // stop just short of the end, so the next sound does not start
+        if remaining <= 0.1 {
+            handle.pause();
+            self.asleep = true;
+            return true;
+        }
+        if !self.fading && remaining <= FADE_OUT {
+            handle.set_volume(0.0, remaining);
+            self.fading = true;
+        } else if self.fading && remaining > FADE_OUT {
+            // seeked back, start over
+            handle.set_volume(1.0, 1.0);
+            self.fading = false;
+        }
+        true
+    }
+
+    /// Cancel the timer, undoing a fade that might be in progress
+    pub fn cancel(&self, active: Option<&mut MetaSound>) {
+        if let Some(h) = active.and_then(|s| s.streamhandle.as_mut()) {
+            if self.fading || self.asleep {
+                h.set_volume(1.0, 1.0);
+            }
+        }
+    }
+}
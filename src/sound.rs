@@ -1,18 +1,16 @@
-use kira::instance::{InstanceSettings, StopInstanceSettings};
-use kira::{
-    instance::handle::InstanceHandle,
-    manager::AudioManager,
-    sound::{handle::SoundHandle, SoundSettings},
-};
+use crate::loudness::ReplayGain;
+use crate::stream::{StreamHandle, StreamManager, StreamState};
 
+use rand::{thread_rng, Rng};
 #[cfg(feature = "persistence")]
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::{
     ffi::OsStr,
     path::{Path, PathBuf},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{anyhow, Error, Result};
@@ -27,6 +25,10 @@ pub trait Playlist {
     fn to_index(&self, _sound: &MetaSound) -> Option<usize> {
         unimplemented!()
     }
+    /// Index of the sound to play once `sound` is done, or `None` to stop
+    fn next_index(&self, sound: &MetaSound, repeat: RepeatMode) -> Option<usize>;
+    /// Index of the sound before `sound`, or `None` at the start unless the playlist repeats
+    fn prev_index(&self, sound: &MetaSound, repeat: RepeatMode) -> Option<usize>;
 }
 
 impl Playlist for SoundQueue {
@@ -42,10 +44,275 @@ impl Playlist for SoundQueue {
         }
         None
     }
+
+    fn next_index(&self, sound: &MetaSound, repeat: RepeatMode) -> Option<usize> {
+        next_position(self.to_index(sound)?, self.len(), repeat)
+    }
+
+    fn prev_index(&self, sound: &MetaSound, repeat: RepeatMode) -> Option<usize> {
+        prev_position(self.to_index(sound)?, self.len(), repeat)
+    }
+}
+
+/// Position after `i` in a list of `len` items
+fn next_position(i: usize, len: usize, repeat: RepeatMode) -> Option<usize> {
+    match repeat {
+        RepeatMode::Off => Some(i + 1).filter(|next| *next < len),
+        RepeatMode::One => Some(i),
+        RepeatMode::All => Some((i + 1) % len),
+    }
+}
+
+/// Position before `i` in a list of `len` items
+fn prev_position(i: usize, len: usize, repeat: RepeatMode) -> Option<usize> {
+    match repeat {
+        RepeatMode::All => Some((i + len - 1) % len),
+        _ => i.checked_sub(1),
+    }
 }
 
 #[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
 #[derive(Debug, Clone, Default)]
+/// A random play order over the playlist, so every sound plays once before any repeats.
+/// The playlist itself keeps its order.
+pub struct ShuffleOrder {
+    /// Paths of the sounds, in the order they play
+    #[serde(deserialize_with = "saved_order")]
+    order: Vec<PathBuf>,
+}
+
+impl ShuffleOrder {
+    /// Shuffle the playlist, starting with `first`
+    pub fn new(queue: &SoundQueue, first: Option<&MetaSound>) -> Self {
+        let mut order = Self::default();
+        // mixing every sound in one after the other shuffles them
+        order.sync(queue, None);
+        if let Some(i) = first.and_then(|f| order.position(f)) {
+            let first = order.order.remove(i);
+            order.order.insert(0, first);
+        }
+        order
+    }
+
+    /// Follow sounds being added to or removed from the playlist.
+    /// New sounds are mixed into the part after `current`, which has not been played yet.
+    pub fn sync(&mut self, queue: &SoundQueue, current: Option<&MetaSound>) {
+        let in_queue = queue.iter().map(|s| &s.path).collect::<HashSet<_>>();
+        self.order.retain(|p| in_queue.contains(p));
+
+        let missing = {
+            let mut in_order = self.order.iter().collect::<HashSet<_>>();
+            queue
+                .iter()
+                .map(|s| &s.path)
+                .filter(|p| in_order.insert(p))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let played = current
+            .and_then(|c| self.position(c))
+            .map(|i| i + 1)
+            .unwrap_or_default();
+        let mut rng = thread_rng();
+        for path in missing {
+            let at = rng.gen_range(played..=self.order.len());
+            self.order.insert(at, path);
+        }
+    }
+
+    fn position(&self, sound: &MetaSound) -> Option<usize> {
+        self.order.iter().position(|p| *p == sound.path)
+    }
+
+    /// Playlist index of the sound at position `i` of the order
+    fn to_queue_index(&self, queue: &SoundQueue, i: usize) -> Option<usize> {
+        queue.iter().position(|s| s.path == self.order[i])
+    }
+
+    /// Playlist index of the sound to play once `sound` is done
+    pub fn next_index(
+        &self,
+        queue: &SoundQueue,
+        sound: &MetaSound,
+        repeat: RepeatMode,
+    ) -> Option<usize> {
+        let i = next_position(self.position(sound)?, self.order.len(), repeat)?;
+        self.to_queue_index(queue, i)
+    }
+
+    pub fn prev_index(
+        &self,
+        queue: &SoundQueue,
+        sound: &MetaSound,
+        repeat: RepeatMode,
+    ) -> Option<usize> {
+        let i = prev_position(self.position(sound)?, self.order.len(), repeat)?;
+        self.to_queue_index(queue, i)
+    }
+}
+
+/// An entry of a saved shuffle order. Older saves kept the whole sound.
+#[cfg(feature = "persistence")]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedEntry {
+    Path(PathBuf),
+    Sound { path: PathBuf },
+}
+
+#[cfg(feature = "persistence")]
+fn saved_order<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    let entries = Vec::<SavedEntry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| match entry {
+            SavedEntry::Path(path) | SavedEntry::Sound { path } => path,
+        })
+        .collect())
+}
+
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[serde(default)]
+#[derive(Debug, Clone)]
+/// Where each sound was left off, so it continues from there when played again
+pub struct ResumePositions {
+    positions: HashMap<PathBuf, f64>,
+    /// When each position was remembered
+    left_at: HashMap<PathBuf, SystemTime>,
+    /// Sounds shorter than this many seconds start from the beginning instead
+    pub min_duration: f64,
+    /// After a break of this many seconds, playback continues a little earlier
+    pub rewind_after: f64,
+    /// Seconds to go back after a long break
+    pub rewind: f64,
+}
+
+impl Default for ResumePositions {
+    fn default() -> Self {
+        Self {
+            positions: HashMap::default(),
+            left_at: HashMap::default(),
+            min_duration: 600.0,
+            rewind_after: 300.0,
+            rewind: 10.0,
+        }
+    }
+}
+
+/// When sounds continue where they were left, and how far back after a break
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResumeSettings {
+    /// Sounds shorter than this many seconds start from the beginning instead
+    pub min_duration: f64,
+    /// After a break of this many seconds, playback continues a little earlier
+    pub rewind_after: f64,
+    /// Seconds to go back after a long break
+    pub rewind: f64,
+}
+
+impl ResumePositions {
+    pub fn settings(&self) -> ResumeSettings {
+        ResumeSettings {
+            min_duration: self.min_duration,
+            rewind_after: self.rewind_after,
+            rewind: self.rewind,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: ResumeSettings) {
+        self.min_duration = settings.min_duration;
+        self.rewind_after = settings.rewind_after;
+        self.rewind = settings.rewind;
+    }
+
+    /// Remember the position of a sound that is stopped or switched away from.
+    /// Finished sounds start over next time.
+    pub fn remember(&mut self, sound: &MetaSound) {
+        let handle = match &sound.streamhandle {
+            Some(h) => h,
+            None => return,
+        };
+        let position = handle.position();
+        // a duration of 0 is unknown, so there is no telling how close to the end it is
+        let duration = handle.duration();
+        let near_end = duration > 0.0 && duration - position < 2.0;
+        if handle.state() == StreamState::Finished || near_end {
+            self.forget(sound);
+        } else if position > 0.0 {
+            self.positions.insert(sound.path.clone(), position);
+            // a sound that sits paused was left when it was paused
+            let left_at = SystemTime::now() - handle.paused_for().unwrap_or_default();
+            self.left_at.insert(sound.path.clone(), left_at);
+        }
+    }
+
+    pub fn forget(&mut self, sound: &MetaSound) {
+        self.positions.remove(&sound.path);
+        self.left_at.remove(&sound.path);
+    }
+
+    /// Seek a freshly loaded sound to where it was left, if it is long enough or of unknown length
+    pub fn resume(&self, sound: &mut MetaSound) {
+        if let Some(h) = &mut sound.streamhandle {
+            let duration = h.duration();
+            if duration > 0.0 && duration < self.min_duration {
+                return;
+            }
+            if let Some(position) = self.positions.get(&sound.path) {
+                let away = self
+                    .left_at
+                    .get(&sound.path)
+                    .and_then(|t| t.elapsed().ok())
+                    .unwrap_or_default();
+                h.seek_to((position - self.rewind_for(away)).max(0.0));
+            }
+        }
+    }
+
+    /// Seconds to jump back after a break of `away`, to get back into context
+    pub fn rewind_for(&self, away: Duration) -> f64 {
+        if away.as_secs_f64() >= self.rewind_after {
+            self.rewind
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// What to play once a sound is done
+pub enum RepeatMode {
+    /// Advance through the playlist and stop at its end
+    #[default]
+    Off,
+    /// Play the same sound again
+    One,
+    /// Advance through the playlist and wrap around at its end
+    All,
+}
+
+impl RepeatMode {
+    /// The mode after this one, for a button that cycles through them
+    pub fn cycle(&self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+
+    pub fn icon(&self) -> &'static str {
+        match self {
+            RepeatMode::One => "🔂",
+            _ => "🔁",
+        }
+    }
+}
+
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[serde(default)]
+#[derive(Debug, Clone)]
 /// A high-level sound
 pub struct MetaSound {
     /// Location of sound
@@ -55,12 +322,50 @@ pub struct MetaSound {
     pub sample_rate: u32,
     pub channels: u16,
     pub duration: Duration,
+    /// Whether playback loops between `loop_start` and `loop_end`
     pub looped: bool,
+    /// Start of the loop region in seconds. Also marks a pending start while `looped` is off.
+    pub loop_start: f64,
+    pub loop_end: f64,
     #[serde(skip)]
-    pub soundhandle: Option<SoundHandle>,
-    #[serde(skip)]
-    pub instancehandle: Option<InstanceHandle>,
+    pub streamhandle: Option<StreamHandle>,
     pub bookmarks: Vec<f64>,
+    /// Playback speed, 1.0 is normal
+    pub speed: f64,
+    pub replaygain: ReplayGain,
+    /// Cut long silences short, for spoken word
+    pub skip_silence: bool,
+}
+
+/// Playback settings of a sound that are kept between plays
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoundSettings {
+    pub speed: f64,
+    pub skip_silence: bool,
+    pub looped: bool,
+    pub loop_start: f64,
+    pub loop_end: f64,
+}
+
+impl Default for MetaSound {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::default(),
+            name: String::default(),
+            sample_rate: 0,
+            channels: 0,
+            duration: Duration::default(),
+            looped: false,
+            loop_start: 0.0,
+            loop_end: 0.0,
+            streamhandle: None,
+            bookmarks: vec![],
+            speed: 1.0,
+            replaygain: ReplayGain::default(),
+            skip_silence: false,
+        }
+    }
 }
 
 impl PartialEq for MetaSound {
@@ -85,10 +390,40 @@ impl MetaSound {
         let album = tag.album().ok_or(anyhow!("Can't read album"))?.title;
         Ok(Self {
             name: format!("{} - {} | {}", artist, title, album),
+            replaygain: ReplayGain::read(&self.path).unwrap_or_default(),
             ..self.clone()
         })
     }
 
+    pub fn settings(&self) -> SoundSettings {
+        SoundSettings {
+            speed: self.speed,
+            skip_silence: self.skip_silence,
+            looped: self.looped,
+            loop_start: self.loop_start,
+            loop_end: self.loop_end,
+        }
+    }
+
+    pub fn with_settings(&self, settings: SoundSettings) -> Self {
+        Self {
+            speed: settings.speed,
+            skip_silence: settings.skip_silence,
+            looped: settings.looped,
+            loop_start: settings.loop_start,
+            loop_end: settings.loop_end,
+            ..self.clone()
+        }
+    }
+
+    /// A copy to keep around, without the stream. Copies holding it keep the stream open.
+    pub fn without_stream(&self) -> Self {
+        Self {
+            streamhandle: None,
+            ..self.clone()
+        }
+    }
+
     pub fn with_path<P: AsRef<Path>>(&self, path: P) -> Self {
         Self {
             path: path.as_ref().into(),
@@ -111,37 +446,105 @@ impl MetaSound {
     pub fn try_meta(&self) -> Self {
         match self.load_tag() {
             Ok(s_id_tag) => s_id_tag,
-            Err(_) => self.clone(),
+            Err(_) => Self {
+                replaygain: ReplayGain::read(&self.path).unwrap_or_default(),
+                ..self.clone()
+            },
+        }
+    }
+
+    /// Opens the sound for streaming. Nothing is decoded up front.
+    pub fn load(&self, manager: &mut StreamManager) -> Result<StreamHandle, Error> {
+        let mut handle = manager.load_stream(&self.path)?;
+        handle.set_speed(self.speed);
+        handle.set_loop(self.loop_region());
+        handle.set_skip_silence(self.skip_silence);
+        handle.set_replaygain(self.replaygain.factor(manager.gain_mode()));
+        Ok(handle)
+    }
+
+    pub fn loop_region(&self) -> Option<(f64, f64)> {
+        if self.looped {
+            Some((self.loop_start, self.loop_end))
+        } else {
+            None
+        }
+    }
+
+    /// Mark the start of the loop region. Looping starts once the end is marked.
+    pub fn set_loop_start(&mut self, start: f64) {
+        self.loop_start = start;
+        self.clear_loop_end();
+    }
+
+    /// Mark the end of the loop region and start looping, if it is after the start.
+    pub fn set_loop_end(&mut self, end: f64) {
+        if end > self.loop_start {
+            self.loop_end = end;
+            self.looped = true;
+            let region = self.loop_region();
+            if let Some(h) = &mut self.streamhandle {
+                h.set_loop(region);
+            }
+        }
+    }
+
+    pub fn clear_loop(&mut self) {
+        self.loop_start = 0.0;
+        self.clear_loop_end();
+    }
+
+    fn clear_loop_end(&mut self) {
+        self.loop_end = 0.0;
+        self.looped = false;
+        if let Some(h) = &mut self.streamhandle {
+            h.set_loop(None);
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        if let Some(h) = &mut self.streamhandle {
+            h.set_speed(speed);
         }
     }
 
-    pub fn load(&self, manager: &mut AudioManager) -> Result<SoundHandle, Error> {
-        manager
-            .load_sound(&self.path, SoundSettings::default())
-            .map_err(|e| anyhow!("{}", e))
+    pub fn set_skip_silence(&mut self, skip_silence: bool) {
+        self.skip_silence = skip_silence;
+        if let Some(h) = &mut self.streamhandle {
+            h.set_skip_silence(skip_silence);
+        }
     }
 
-    pub fn load_soundhandle(&self, manager: &mut AudioManager) -> Self {
+    pub fn load_streamhandle(&self, manager: &mut StreamManager) -> Self {
         let handle = self.load(manager).ok();
         Self {
-            soundhandle: handle,
+            duration: handle
+                .as_ref()
+                .map(|h| Duration::from_secs_f64(h.duration()))
+                .unwrap_or(self.duration),
+            sample_rate: handle
+                .as_ref()
+                .map(|h| h.sample_rate)
+                .unwrap_or(self.sample_rate),
+            channels: handle.as_ref().map(|h| h.channels).unwrap_or(self.channels),
+            streamhandle: handle,
             ..self.clone()
         }
     }
 
     pub fn play(&mut self) -> Result<()> {
-        let soundhandle = self
-            .soundhandle
+        let streamhandle = self
+            .streamhandle
             .as_mut()
-            .ok_or(anyhow!("Sound handle is None. Is this sound loaded?"))?;
-        let instancehandle = soundhandle.play(InstanceSettings::new())?;
-        self.instancehandle = Some(instancehandle);
+            .ok_or(anyhow!("Stream handle is None. Is this sound loaded?"))?;
+        streamhandle.resume();
         Ok(())
     }
 
-    pub fn play_load_mut(&mut self, manager: &mut AudioManager) -> Result<()> {
-        self.soundhandle = self.load(manager).ok();
-        if let Some(handle) = &self.soundhandle {
+    pub fn play_load_mut(&mut self, manager: &mut StreamManager) -> Result<()> {
+        self.streamhandle = self.load(manager).ok();
+        if let Some(handle) = &self.streamhandle {
             self.duration = Duration::from_secs_f64(handle.duration());
         }
         self.play()?;
@@ -149,8 +552,8 @@ impl MetaSound {
     }
 
     pub fn stop(&mut self) {
-        if let Some(h) = &mut self.soundhandle {
-            let _ = h.stop(StopInstanceSettings::new());
+        if let Some(h) = &mut self.streamhandle {
+            h.stop();
         }
     }
 }
@@ -162,3 +565,169 @@ pub fn nice_name(p: &Path) -> String {
         .replace("_", " ")
         .replace("-", " ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{tone_sound, tone_wav};
+
+    fn sound(path: &str) -> MetaSound {
+        MetaSound::default().with_path(path)
+    }
+
+    fn queue(paths: &[&str]) -> SoundQueue {
+        paths.iter().map(|p| sound(p)).collect()
+    }
+
+    #[test]
+    fn sounds_are_the_same_by_path() {
+        let a = MetaSound {
+            speed: 2.0,
+            bookmarks: vec![1.0],
+            ..sound("/music/a.mp3")
+        };
+        assert_eq!(a, sound("/music/a.mp3"));
+        assert_ne!(a, sound("/other/a.mp3"));
+    }
+
+    #[test]
+    fn playlist_contains() {
+        let queue = queue(&["/music/a.mp3", "/music/b.mp3"]);
+        assert!(queue.contains(&sound("/music/b.mp3")));
+        assert!(!queue.contains(&sound("/music/c.mp3")));
+        assert!(!SoundQueue::new().contains(&sound("/music/a.mp3")));
+    }
+
+    #[test]
+    fn playlist_to_index() {
+        let queue = queue(&["/music/a.mp3", "/music/b.mp3", "/music/a.mp3"]);
+        assert_eq!(queue.to_index(&sound("/music/b.mp3")), Some(1));
+        // the first of several
+        assert_eq!(queue.to_index(&sound("/music/a.mp3")), Some(0));
+        assert_eq!(queue.to_index(&sound("/music/c.mp3")), None);
+    }
+
+    #[test]
+    fn playlist_next_index() {
+        let queue = queue(&["a.mp3", "b.mp3", "c.mp3"]);
+        assert_eq!(queue.next_index(&sound("a.mp3"), RepeatMode::Off), Some(1));
+        assert_eq!(queue.next_index(&sound("c.mp3"), RepeatMode::Off), None);
+        assert_eq!(queue.next_index(&sound("c.mp3"), RepeatMode::All), Some(0));
+        assert_eq!(queue.next_index(&sound("b.mp3"), RepeatMode::One), Some(1));
+        assert_eq!(queue.next_index(&sound("d.mp3"), RepeatMode::All), None);
+    }
+
+    #[test]
+    fn playlist_prev_index() {
+        let queue = queue(&["a.mp3", "b.mp3", "c.mp3"]);
+        assert_eq!(queue.prev_index(&sound("b.mp3"), RepeatMode::Off), Some(0));
+        assert_eq!(queue.prev_index(&sound("a.mp3"), RepeatMode::Off), None);
+        assert_eq!(queue.prev_index(&sound("a.mp3"), RepeatMode::One), None);
+        assert_eq!(queue.prev_index(&sound("a.mp3"), RepeatMode::All), Some(2));
+        assert_eq!(queue.prev_index(&sound("d.mp3"), RepeatMode::All), None);
+    }
+
+    #[test]
+    fn shuffle_order_plays_everything_once() {
+        let queue = queue(&["a.mp3", "b.mp3", "c.mp3", "d.mp3"]);
+        let order = ShuffleOrder::new(&queue, Some(&queue[2]));
+        let mut played = vec![2];
+        while let Some(i) =
+            order.next_index(&queue, &queue[*played.last().unwrap()], RepeatMode::Off)
+        {
+            played.push(i);
+        }
+        played.sort_unstable();
+        assert_eq!(played, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn shuffle_order_follows_the_playlist() {
+        let mut queue = queue(&["a.mp3", "b.mp3", "c.mp3"]);
+        let first = queue[0].clone();
+        let mut order = ShuffleOrder::new(&queue, Some(&first));
+        let current = order.next_index(&queue, &first, RepeatMode::Off).unwrap();
+        let current = queue[current].clone();
+        // drop the sound left to play, and add another one twice
+        queue.retain(|s| s == &first || s == &current);
+        queue.push(sound("d.mp3"));
+        queue.push(sound("d.mp3"));
+        order.sync(&queue, Some(&current));
+        assert_eq!(order.order.len(), 3);
+        // new sounds come after the ones played
+        assert_eq!(order.position(&first), Some(0));
+        assert_eq!(order.position(&current), Some(1));
+        assert_eq!(order.next_index(&queue, &current, RepeatMode::Off), Some(2));
+    }
+
+    #[test]
+    fn nice_names() {
+        assert_eq!(
+            nice_name(Path::new("/music/Some_Band-Song.mp3")),
+            "Some Band Song.mp3"
+        );
+        assert_eq!(nice_name(Path::new("plain.wav")), "plain.wav");
+        assert_eq!(nice_name(Path::new("/")), "no path");
+        assert_eq!(sound("/music/a_b.flac").name, "a b.flac");
+    }
+
+    #[test]
+    fn supported_formats() {
+        for path in &["a.mp3", "a.m4a", "a.ogg", "a.flac", "/dir.with.dots/a.wav"] {
+            assert!(sound(path).is_supported(), "{}", path);
+        }
+        for path in &["a.txt", "a", "mp3", "/music.mp3/cover.jpg"] {
+            assert!(!sound(path).is_supported(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn try_meta_falls_back_without_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let untagged = tone_sound(dir.path(), "Untagged_Tone.wav", 0.1);
+        assert!(untagged.load_tag().is_err());
+        let meta = untagged.try_meta();
+        assert_eq!(meta.path, untagged.path);
+        assert_eq!(meta.name, "Untagged Tone.wav");
+        assert!(meta.replaygain.is_empty());
+
+        // not even a file
+        let missing = sound("/does/not/exist.mp3").try_meta();
+        assert_eq!(missing.name, "exist.mp3");
+    }
+
+    #[test]
+    fn load_streamhandle_fills_in_the_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = crate::testing::offline_manager();
+        let loaded = sound(tone_wav(dir.path(), "a.wav", 1.5).to_str().unwrap())
+            .load_streamhandle(&mut manager);
+        assert!(loaded.streamhandle.is_some());
+        assert_eq!(loaded.sample_rate, crate::testing::SAMPLE_RATE);
+        assert_eq!(loaded.channels, 2);
+        assert!((loaded.duration.as_secs_f64() - 1.5).abs() < 0.01);
+
+        let missing = sound("/does/not/exist.wav").load_streamhandle(&mut manager);
+        assert!(missing.streamhandle.is_none());
+    }
+
+    #[test]
+    fn resumes_sounds_of_unknown_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = crate::testing::offline_manager();
+        let mut resume = ResumePositions::default();
+        let mut sound = sound(tone_wav(dir.path(), "a.wav", 1.5).to_str().unwrap())
+            .load_streamhandle(&mut manager);
+        let handle = sound.streamhandle.as_mut().unwrap();
+        handle.forget_duration();
+        handle.resume();
+        crate::testing::play_for(&mut manager, 1.0);
+        handle.pause();
+        resume.remember(&sound);
+
+        let mut again = sound.load_streamhandle(&mut manager);
+        again.streamhandle.as_mut().unwrap().forget_duration();
+        resume.resume(&mut again);
+        assert!(again.streamhandle.unwrap().position() > 0.8);
+    }
+}
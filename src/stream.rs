@@ -0,0 +1,1394 @@
+use kira::{
+    audio_stream::AudioStream,
+    manager::{AudioManager, AudioManagerSettings},
+    mixer::{effect::EffectSettings, MainTrackHandle, TrackIndex},
+    Frame,
+};
+use log::{debug, error, info};
+#[cfg(feature = "persistence")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+    units::{Time, TimeBase},
+};
+
+use crate::dsp::{self, ChainSlot, ChannelSettings, DspChainHandle, OutputHandle};
+use crate::equalizer::{self, EqualizerHandle, Gains};
+use crate::loudness::GainMode;
+use crate::output::{
+    output_devices, AudioOutput, OutputKind, Retry, SharedBackend, BACKEND_SAMPLE_RATE,
+};
+use crate::silence::SilenceDetector;
+use crate::stretch::TimeStretch;
+use anyhow::{anyhow, Result};
+
+/// How many decoded packets are buffered ahead of the playback position.
+/// Packets are a few thousand frames at most, so this is well below a second of audio.
+const BUFFERED_CHUNKS: usize = 64;
+
+/// Owns the kira manager and the single audio stream all decoded sounds are mixed into
+pub struct StreamManager {
+    // fields drop in order, and the backend has to go before the manager cleans up after it
+    output: Option<Box<dyn AudioOutput>>,
+    backend: SharedBackend,
+    manager: AudioManager,
+    /// What to play to
+    kind: OutputKind,
+    /// Why there is no output, while there is none
+    output_error: Option<String>,
+    retry: Retry,
+    voices: Sender<Voice>,
+    /// Whether new streams keep their pitch when sped up
+    preserve_pitch: bool,
+    fades: Arc<Mutex<FadePolicy>>,
+    /// ReplayGain applied to streams loaded afterwards, never `Auto`
+    gain_mode: GainMode,
+    equalizer: EqualizerHandle,
+    effects: DspChainHandle,
+    stage: OutputHandle,
+}
+
+impl StreamManager {
+    /// Plays on the output device called `device`, or the default device for `None`.
+    /// Without a device to play on, this still succeeds and `keep_alive` tries again later.
+    pub fn new(settings: AudioManagerSettings, device: Option<&str>) -> Result<Self> {
+        Self::with_output(settings, OutputKind::Device(device.map(|d| d.to_string())))
+    }
+
+    /// Plays to `kind`, which need not be a sound card
+    pub fn with_output(settings: AudioManagerSettings, kind: OutputKind) -> Result<Self> {
+        // we drive the backend ourselves, to pick what it plays to
+        let (mut manager, backend) = AudioManager::new_without_audio_thread(settings);
+        let backend = Arc::new(Mutex::new(backend));
+        let (voices, incoming) = channel();
+        manager
+            .add_stream(
+                MixerStream {
+                    incoming,
+                    voices: vec![],
+                },
+                TrackIndex::Main,
+            )
+            .map_err(|e| anyhow!("{}", e))?;
+        let (effect, equalizer) = equalizer::equalizer();
+        manager
+            .main_track()
+            .add_effect(effect, EffectSettings::new())
+            .map_err(|e| anyhow!("{}", e))?;
+        // effects run in the order they are added, so the chain comes after the equalizer
+        let (chain, effects) = dsp::dsp_chain();
+        manager
+            .main_track()
+            .add_effect(chain, EffectSettings::new())
+            .map_err(|e| anyhow!("{}", e))?;
+        // the track volume comes after all effects, so the output stage applies it instead
+        let (effect, stage) = dsp::output();
+        manager
+            .main_track()
+            .add_effect(effect, EffectSettings::new())
+            .map_err(|e| anyhow!("{}", e))?;
+        let mut stream_manager = Self {
+            output: None,
+            backend,
+            manager,
+            kind,
+            output_error: None,
+            retry: Retry::default(),
+            voices,
+            preserve_pitch: true,
+            fades: Arc::new(Mutex::new(FadePolicy::default())),
+            gain_mode: GainMode::Off,
+            equalizer,
+            effects,
+            stage,
+        };
+        if let Err(e) = stream_manager.open_output() {
+            error!("No audio output: {}", e);
+        }
+        Ok(stream_manager)
+    }
+
+    pub fn main_track(&mut self) -> MainTrackHandle {
+        self.manager.main_track()
+    }
+
+    /// The output device asked for, `None` for the default one
+    pub fn device(&self) -> Option<&str> {
+        match &self.kind {
+            OutputKind::Device(device) => device.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Name of the device that is playing
+    pub fn device_name(&self) -> Option<&str> {
+        self.output.as_ref().map(|o| o.name())
+    }
+
+    /// Move playback to another device. Sounds carry on where they are, as only the
+    /// device is replaced.
+    pub fn set_device(&mut self, device: Option<&str>) -> Result<()> {
+        self.kind = OutputKind::Device(device.map(|d| d.to_string()));
+        self.open_output()
+    }
+
+    /// Play `seconds` of audio right away, into nothing. For an `OutputKind::Offline`
+    /// manager, this is the only way time passes.
+    pub fn advance(&mut self, seconds: f64) {
+        if let Ok(mut backend) = self.backend.lock() {
+            for _ in 0..(seconds * BACKEND_SAMPLE_RATE as f64).round() as usize {
+                backend.process();
+            }
+        }
+    }
+
+    /// Like `advance`, but returns what was played
+    #[cfg(test)]
+    pub fn record(&mut self, seconds: f64) -> Vec<Frame> {
+        let mut backend = self.backend.lock().unwrap();
+        (0..(seconds * BACKEND_SAMPLE_RATE as f64).round() as usize)
+            .map(|_| backend.process())
+            .collect()
+    }
+
+    /// Why nothing can be heard, if the device could not be opened or went away
+    pub fn output_error(&self) -> Option<&str> {
+        self.output_error.as_deref()
+    }
+
+    /// Open the device again right away
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.open_output()
+    }
+
+    /// Notice when the device goes away, and try to get it back every few seconds.
+    /// Streams stand still meanwhile, so they continue where they were once it is back.
+    /// Only without any sound card at all, they play on into nothing.
+    pub fn keep_alive(&mut self) {
+        let lost = self.output.as_ref().map(|o| o.is_lost());
+        if lost.unwrap_or_default() {
+            self.output = None;
+            self.output_error = Some("The audio device went away".to_string());
+        }
+        if self.output_error.is_some() && self.retry.due() && self.open_output().is_ok() {
+            info!("Audio output is back");
+        }
+    }
+
+    /// Open the requested device, in place of the current one
+    fn open_output(&mut self) -> Result<()> {
+        // close the old device first, so the two don't both pull audio
+        self.output = None;
+        self.retry.attempted();
+        match self.kind.open(self.backend.clone()) {
+            Ok(output) => {
+                self.output = output;
+                self.output_error = None;
+                Ok(())
+            }
+            Err(e) => {
+                self.output_error = Some(e.to_string());
+                if let Some(fallback) = self.kind.fallback(&output_devices()) {
+                    self.output = fallback.open(self.backend.clone()).ok().flatten();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn preserve_pitch(&self) -> bool {
+        self.preserve_pitch
+    }
+
+    /// Applies to streams loaded afterwards
+    pub fn set_preserve_pitch(&mut self, preserve_pitch: bool) {
+        self.preserve_pitch = preserve_pitch;
+    }
+
+    /// Gains of the equalizer bands in dB
+    pub fn equalizer(&self) -> Gains {
+        self.equalizer.gains()
+    }
+
+    pub fn set_equalizer(&mut self, gains: Gains) {
+        self.equalizer.set_gains(gains);
+    }
+
+    /// The effect chain on the main track
+    pub fn effects(&self) -> &[ChainSlot] {
+        self.effects.slots()
+    }
+
+    pub fn set_effects(&mut self, slots: &[ChainSlot]) {
+        self.effects.set_slots(slots);
+    }
+
+    pub fn volume(&self) -> f64 {
+        self.stage.volume()
+    }
+
+    /// Master volume, applied before the night mode limiter
+    pub fn set_volume(&mut self, volume: f64) {
+        self.stage.set_volume(volume);
+    }
+
+    pub fn night_mode(&self) -> bool {
+        self.stage.night_mode()
+    }
+
+    /// Compress and limit the output, for quiet listening
+    pub fn set_night_mode(&mut self, night_mode: bool) {
+        self.stage.set_night_mode(night_mode);
+    }
+
+    pub fn channels(&self) -> ChannelSettings {
+        self.stage.channels()
+    }
+
+    /// Balance, mono fold-down and channel swap of the output
+    pub fn set_channels(&mut self, channels: ChannelSettings) {
+        self.stage.set_channels(channels);
+    }
+
+    pub fn gain_mode(&self) -> GainMode {
+        self.gain_mode
+    }
+
+    pub fn set_gain_mode(&mut self, mode: GainMode) {
+        self.gain_mode = mode;
+    }
+
+    pub fn fades(&self) -> FadePolicy {
+        self.fades.lock().map(|f| *f).unwrap_or_default()
+    }
+
+    /// Applies to all streams, including those already playing
+    pub fn set_fades(&mut self, fades: FadePolicy) {
+        if let Ok(mut f) = self.fades.lock() {
+            *f = fades;
+        }
+    }
+
+    /// Open a sound for streaming. It starts out paused, but decoding starts right away
+    /// so the first packets are ready once it is resumed.
+    pub fn load_stream<P: AsRef<Path>>(&mut self, path: P) -> Result<StreamHandle> {
+        let path = path.as_ref();
+        let mut decoding = Decoding::open(path)?;
+
+        let shared = Arc::new(Shared::default());
+        decoding.shared = Arc::downgrade(&shared);
+        shared
+            .preserve_pitch
+            .store(self.preserve_pitch, Ordering::Relaxed);
+        let (chunk_sender, chunks) = sync_channel(BUFFERED_CHUNKS);
+        let (seeks, seek_receiver) = channel();
+
+        let handle = StreamHandle {
+            shared: shared.clone(),
+            seeks,
+            fades: self.fades.clone(),
+            duration: decoding.duration(),
+            chapters: decoding.chapters(),
+            sample_rate: decoding.sample_rate,
+            channels: decoding.channels,
+        };
+
+        let voice = Voice::new(shared, chunks, handle.seeks.clone(), decoding.sample_rate);
+
+        thread::Builder::new()
+            .name(format!("decode {}", path.display()))
+            .spawn(move || decoding.run(chunk_sender, seek_receiver))?;
+
+        self.voices
+            .send(voice)
+            .map_err(|_| anyhow!("Audio stream is gone"))?;
+        info!("Streaming {}", path.display());
+        Ok(handle)
+    }
+}
+
+impl Drop for StreamManager {
+    /// Without a device nothing takes the queued commands off the manager, and the
+    /// resources in them would keep it from cleaning up.
+    fn drop(&mut self) {
+        self.output = None;
+        if let Ok(mut backend) = self.backend.lock() {
+            backend.process();
+        }
+    }
+}
+
+/// How long each transport action fades, in seconds. Cutting the sound off mid-wave clicks.
+#[cfg_attr(feature = "persistence", derive(Deserialize, Serialize))]
+#[serde(default)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FadePolicy {
+    pub pause: f64,
+    pub resume: f64,
+    pub stop: f64,
+    pub seek: f64,
+}
+
+impl Default for FadePolicy {
+    fn default() -> Self {
+        Self {
+            pause: 0.2,
+            resume: 0.2,
+            stop: 0.3,
+            seek: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Pause,
+    Resume,
+    Stop,
+    Seek,
+}
+
+impl FadePolicy {
+    pub fn fade(&self, action: Transport) -> f64 {
+        match action {
+            Transport::Pause => self.pause,
+            Transport::Resume => self.resume,
+            Transport::Stop => self.stop,
+            Transport::Seek => self.seek,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    Playing,
+    Paused,
+    Stopped,
+    /// Reached the end of the sound
+    Finished,
+}
+
+impl StreamState {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => StreamState::Playing,
+            1 => StreamState::Paused,
+            2 => StreamState::Stopped,
+            _ => StreamState::Finished,
+        }
+    }
+}
+
+/// State shared between a `StreamHandle` and the mixer running on the audio thread
+#[derive(Debug)]
+struct Shared {
+    /// Playback position in seconds, as f64 bits
+    position: AtomicU64,
+    state: AtomicU8,
+    /// Increased on every seek, so audio decoded before the seek can be thrown away
+    generation: AtomicU64,
+    /// Stream to start as soon as this one reaches its end
+    follower: Mutex<Option<Arc<Shared>>>,
+    /// Volume the gain is moving towards
+    volume: AtomicU64,
+    /// Gain currently applied, moved by the audio thread
+    gain: AtomicU64,
+    /// How fast the gain moves towards the volume, per second
+    ramp: AtomicU64,
+    /// Playback speed, 1.0 is normal
+    speed: AtomicU64,
+    /// Time stretch instead of resampling when the speed changes
+    preserve_pitch: AtomicBool,
+    /// Region to play over and over, in seconds. An end of 0 means no loop.
+    loop_start: AtomicU64,
+    loop_end: AtomicU64,
+    /// When the stream was last paused
+    paused_at: Mutex<Option<Instant>>,
+    /// Loudness normalization factor, applied on top of the volume
+    replaygain: AtomicU64,
+    /// Length in seconds of the fade for the latest transport action
+    fade: AtomicU64,
+    /// Position to jump to once faded out, NaN if none
+    pending_seek: AtomicU64,
+    /// Shorten long silences
+    skip_silence: AtomicBool,
+    /// Seconds of silence skipped so far
+    silence_skipped: AtomicU64,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Self {
+            position: AtomicU64::new(0f64.to_bits()),
+            state: AtomicU8::new(StreamState::Paused as u8),
+            generation: AtomicU64::new(0),
+            follower: Mutex::new(None),
+            volume: AtomicU64::new(1f64.to_bits()),
+            gain: AtomicU64::new(1f64.to_bits()),
+            ramp: AtomicU64::new(f64::INFINITY.to_bits()),
+            speed: AtomicU64::new(1f64.to_bits()),
+            preserve_pitch: AtomicBool::new(true),
+            loop_start: AtomicU64::new(0f64.to_bits()),
+            loop_end: AtomicU64::new(0f64.to_bits()),
+            paused_at: Mutex::new(None),
+            replaygain: AtomicU64::new(1f64.to_bits()),
+            fade: AtomicU64::new(0f64.to_bits()),
+            pending_seek: AtomicU64::new(f64::NAN.to_bits()),
+            skip_silence: AtomicBool::new(false),
+            silence_skipped: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+}
+
+fn load_f64(v: &AtomicU64) -> f64 {
+    f64::from_bits(v.load(Ordering::Relaxed))
+}
+
+fn store_f64(v: &AtomicU64, value: f64) {
+    v.store(value.to_bits(), Ordering::Relaxed);
+}
+
+impl Shared {
+    fn position(&self) -> f64 {
+        load_f64(&self.position)
+    }
+
+    fn set_position(&self, position: f64) {
+        store_f64(&self.position, position);
+    }
+
+    /// Move the gain one step of `dt` seconds towards the volume and return it.
+    fn next_gain(&self, dt: f64) -> f32 {
+        let gain = load_f64(&self.gain);
+        let volume = load_f64(&self.volume);
+        if gain == volume {
+            return gain as f32;
+        }
+        let step = load_f64(&self.ramp) * dt;
+        let gain = if gain < volume {
+            (gain + step).min(volume)
+        } else {
+            (gain - step).max(volume)
+        };
+        store_f64(&self.gain, gain);
+        gain as f32
+    }
+
+    fn state(&self) -> StreamState {
+        StreamState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    fn set_state(&self, state: StreamState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Ask the decoder to continue at `position`, and drop everything decoded so far.
+    fn seek(&self, seeks: &Sender<Seek>, position: f64) {
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        self.set_position(position);
+        let _ = seeks.send(Seek {
+            generation,
+            position,
+        });
+    }
+
+    /// Start and end of the loop region, if there is one
+    fn loop_region(&self) -> Option<(f64, f64)> {
+        let end = load_f64(&self.loop_end);
+        if end > 0.0 {
+            Some((load_f64(&self.loop_start), end))
+        } else {
+            None
+        }
+    }
+
+    fn pending_seek(&self) -> Option<f64> {
+        Some(load_f64(&self.pending_seek)).filter(|p| !p.is_nan())
+    }
+
+    fn stretches(&self, speed: f64) -> bool {
+        speed != 1.0 && self.preserve_pitch.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct Seek {
+    generation: u64,
+    position: f64,
+}
+
+/// Controls a sound that is being streamed
+#[derive(Debug, Clone)]
+pub struct StreamHandle {
+    shared: Arc<Shared>,
+    seeks: Sender<Seek>,
+    fades: Arc<Mutex<FadePolicy>>,
+    duration: f64,
+    /// Chapter starts in seconds
+    pub chapters: Vec<f64>,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl StreamHandle {
+    /// Current position in seconds
+    pub fn position(&self) -> f64 {
+        self.shared
+            .pending_seek()
+            .unwrap_or_else(|| self.shared.position())
+    }
+
+    /// Length in seconds, or 0 if the container does not tell.
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+
+    /// Act as if the container did not tell the length
+    #[cfg(test)]
+    pub fn forget_duration(&mut self) {
+        self.duration = 0.0;
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.shared.state()
+    }
+
+    /// The volume this stream is at, or fading towards
+    pub fn volume(&self) -> f64 {
+        load_f64(&self.shared.volume)
+    }
+
+    /// Fade linearly to `volume` within `fade` seconds. A fade of 0 applies it right away.
+    pub fn set_volume(&mut self, volume: f64, fade: f64) {
+        if fade > 0.0 {
+            let distance = (volume - load_f64(&self.shared.gain)).abs();
+            store_f64(&self.shared.ramp, distance / fade);
+        } else {
+            store_f64(&self.shared.ramp, f64::INFINITY);
+            store_f64(&self.shared.gain, volume);
+        }
+        store_f64(&self.shared.volume, volume);
+    }
+
+    /// Scale the sound by a loudness normalization factor, independent of the volume
+    pub fn set_replaygain(&mut self, factor: f64) {
+        store_f64(&self.shared.replaygain, factor);
+    }
+
+    pub fn speed(&self) -> f64 {
+        load_f64(&self.shared.speed)
+    }
+
+    /// Change the playback speed. Unless the pitch is preserved, it changes along with it.
+    pub fn set_speed(&mut self, speed: f64) {
+        store_f64(&self.shared.speed, speed);
+    }
+
+    /// Time stretch the sound when it is sped up, instead of plain resampling
+    pub fn set_preserve_pitch(&mut self, preserve_pitch: bool) {
+        self.shared
+            .preserve_pitch
+            .store(preserve_pitch, Ordering::Relaxed);
+    }
+
+    /// Cut silences in the sound short, as spoken word often has long pauses
+    pub fn set_skip_silence(&mut self, skip_silence: bool) {
+        self.shared
+            .skip_silence
+            .store(skip_silence, Ordering::Relaxed);
+    }
+
+    /// Seconds of the sound skipped as silence so far
+    pub fn silence_skipped(&self) -> f64 {
+        load_f64(&self.shared.silence_skipped)
+    }
+
+    /// Play the region between two positions over and over, or `None` to play on normally.
+    pub fn set_loop(&mut self, region: Option<(f64, f64)>) {
+        let (start, end) = region.unwrap_or_default();
+        store_f64(&self.shared.loop_start, start);
+        store_f64(&self.shared.loop_end, end);
+    }
+
+    /// Every transport action goes through here, to fade by the policy for it.
+    /// Returns the length of the fade.
+    fn transport(&self, action: Transport) -> f64 {
+        let fade = self
+            .fades
+            .lock()
+            .map(|f| f.fade(action))
+            .unwrap_or_default();
+        store_f64(&self.shared.fade, fade);
+        fade
+    }
+
+    pub fn pause(&mut self) {
+        if self.state() == StreamState::Playing {
+            self.transport(Transport::Pause);
+            self.shared.set_state(StreamState::Paused);
+            if let Ok(mut paused_at) = self.shared.paused_at.lock() {
+                *paused_at = Some(Instant::now());
+            }
+        }
+    }
+
+    /// How long the stream has been paused, if it was paused by `pause`
+    pub fn paused_for(&self) -> Option<Duration> {
+        if self.state() != StreamState::Paused {
+            return None;
+        }
+        let paused_at = *self.shared.paused_at.lock().ok()?;
+        paused_at.map(|t| t.elapsed())
+    }
+
+    pub fn resume(&mut self) {
+        if self.state() == StreamState::Paused {
+            self.transport(Transport::Resume);
+            self.shared.set_state(StreamState::Playing);
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.transport(Transport::Stop);
+        self.shared.set_state(StreamState::Stopped);
+        if let Ok(mut follower) = self.shared.follower.lock() {
+            *follower = None;
+        }
+    }
+
+    /// Resume `next` on the audio thread the moment this stream finishes, so there is no gap.
+    /// `next` should be loaded well ahead, so its first packets are decoded already.
+    pub fn set_follower(&mut self, next: Option<&StreamHandle>) {
+        if let Ok(mut follower) = self.shared.follower.lock() {
+            *follower = next.map(|n| n.shared.clone());
+        }
+    }
+
+    pub fn has_follower(&self) -> bool {
+        self.shared
+            .follower
+            .lock()
+            .map(|f| f.is_some())
+            .unwrap_or_default()
+    }
+
+    /// Jump to a position in seconds. A finished sound starts playing again.
+    pub fn seek_to(&mut self, position: f64) {
+        let position = if self.duration > 0.0 {
+            position.max(0.0).min(self.duration)
+        } else {
+            position.max(0.0)
+        };
+        // a playing sound fades out first, the audio thread seeks once it is silent
+        if self.state() == StreamState::Playing && self.transport(Transport::Seek) > 0.0 {
+            store_f64(&self.shared.pending_seek, position);
+            return;
+        }
+        store_f64(&self.shared.pending_seek, f64::NAN);
+        self.shared.seek(&self.seeks, position);
+        if self.state() == StreamState::Finished {
+            self.shared.set_state(StreamState::Playing);
+        }
+    }
+}
+
+/// A run of decoded frames
+#[derive(Debug)]
+struct Chunk {
+    generation: u64,
+    /// Time of the first frame in seconds
+    start: f64,
+    frames: Vec<Frame>,
+    /// Marks the end of the sound
+    end: bool,
+    /// Set on the first chunk after jumping back to the start of this loop region
+    loop_back: Option<(f64, f64)>,
+}
+
+/// The decoder side of a stream, running on its own thread
+struct Decoding {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    n_frames: Option<u64>,
+    sample_rate: u32,
+    channels: u16,
+    generation: u64,
+    /// Frames before this time are dropped, as seeking lands on packet boundaries
+    skip_until: f64,
+    /// The stream being decoded for, to follow its loop region.
+    /// Weak, so the voice can still tell when all handles are gone.
+    shared: Weak<Shared>,
+    /// The loop region jumped back in, until the next chunk is decoded
+    looped: Option<(f64, f64)>,
+}
+
+impl Decoding {
+    fn open(path: &Path) -> Result<Self> {
+        let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+        let format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )?
+            .format;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(anyhow!("No audio track in {}", path.display()))?;
+        let params = &track.codec_params;
+        let sample_rate = params
+            .sample_rate
+            .ok_or(anyhow!("Unknown sample rate for {}", path.display()))?;
+        let decoder = symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+
+        Ok(Self {
+            track_id: track.id,
+            time_base: params.time_base,
+            n_frames: params.n_frames,
+            sample_rate,
+            channels: params.channels.map(|c| c.count() as u16).unwrap_or(2),
+            format,
+            decoder,
+            generation: 0,
+            skip_until: 0.0,
+            shared: Weak::new(),
+            looped: None,
+        })
+    }
+
+    fn seconds(&self, ts: u64) -> f64 {
+        match self.time_base {
+            Some(tb) => {
+                let time = tb.calc_time(ts);
+                time.seconds as f64 + time.frac
+            }
+            None => ts as f64 / self.sample_rate as f64,
+        }
+    }
+
+    fn duration(&self) -> f64 {
+        self.n_frames.map(|n| self.seconds(n)).unwrap_or_default()
+    }
+
+    /// Chapter starts in seconds, if the container has cues
+    fn chapters(&self) -> Vec<f64> {
+        self.format
+            .cues()
+            .iter()
+            .map(|cue| self.seconds(cue.start_ts))
+            .collect()
+    }
+
+    /// Decode until the voice goes away, or the end is reached and all handles are dropped.
+    fn run(mut self, chunks: SyncSender<Chunk>, seeks: Receiver<Seek>) {
+        let mut at_end = false;
+        loop {
+            // only the most recent seek matters
+            let seek = if at_end {
+                // nothing left to decode, so wait for someone to seek back
+                match seeks.recv() {
+                    Ok(seek) => Some(seek),
+                    Err(_) => return,
+                }
+            } else {
+                seeks.try_iter().last()
+            };
+            let seek = seek.map(|first| seeks.try_iter().last().unwrap_or(first));
+            if let Some(seek) = seek {
+                self.seek(seek);
+                at_end = false;
+            }
+
+            let chunk = match self.next_chunk() {
+                Ok(Some(chunk)) => self.cut_at_loop_end(chunk),
+                // a loop reaching past the end starts over from there, unless nothing
+                // was left to play since the last time
+                Ok(None) => match self.loop_region().filter(|_| self.looped.is_none()) {
+                    Some(region) => {
+                        self.loop_back(region);
+                        continue;
+                    }
+                    None => {
+                        at_end = true;
+                        self.end_chunk()
+                    }
+                },
+                Err(e) => {
+                    error!("Decoding failed: {}", e);
+                    at_end = true;
+                    self.end_chunk()
+                }
+            };
+            if chunks.send(chunk).is_err() {
+                debug!("Stream was dropped, stop decoding");
+                return;
+            }
+        }
+    }
+
+    fn seek(&mut self, seek: Seek) {
+        self.generation = seek.generation;
+        self.looped = None;
+        self.seek_to(seek.position);
+    }
+
+    fn seek_to(&mut self, position: f64) {
+        let to = SeekTo::Time {
+            time: Time::from(position),
+            track_id: Some(self.track_id),
+        };
+        match self.format.seek(SeekMode::Accurate, to) {
+            Ok(_) => self.skip_until = position,
+            Err(e) => error!("Can't seek to {}: {}", position, e),
+        }
+        self.decoder.reset();
+    }
+
+    fn loop_region(&self) -> Option<(f64, f64)> {
+        self.shared.upgrade()?.loop_region()
+    }
+
+    /// Continue decoding at the start of the loop. Unlike a seek, what is buffered stays,
+    /// so playback reaches the jump without a gap.
+    fn loop_back(&mut self, region: (f64, f64)) {
+        self.seek_to(region.0);
+        self.looped = Some(region);
+    }
+
+    /// Leave out what comes after the end of the loop, and jump back once it is reached.
+    /// A chunk starting past the end is left alone, the voice seeks back then.
+    fn cut_at_loop_end(&mut self, mut chunk: Chunk) -> Chunk {
+        let region = match self.loop_region() {
+            Some(region) => region,
+            None => return chunk,
+        };
+        let keep = ((region.1 - chunk.start) * self.sample_rate as f64).round();
+        if keep > 0.0 && (keep as usize) < chunk.frames.len() {
+            chunk.frames.truncate(keep as usize);
+            self.loop_back(region);
+        }
+        chunk
+    }
+
+    fn end_chunk(&self) -> Chunk {
+        Chunk {
+            generation: self.generation,
+            start: self.duration(),
+            frames: vec![],
+            end: true,
+            loop_back: None,
+        }
+    }
+
+    /// Decode the next packet of our track. Returns `None` at the end of the file.
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(e)) => {
+                    debug!("Skipping broken packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let spec = *decoded.spec();
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            samples.copy_interleaved_ref(decoded);
+
+            let mut start = self.seconds(packet.ts());
+            let mut frames = samples
+                .samples()
+                .chunks(spec.channels.count().max(1))
+                .map(|s| match s {
+                    [mono] => Frame::from_mono(*mono),
+                    [left, right, ..] => Frame::new(*left, *right),
+                    _ => Frame::from_mono(0.0),
+                })
+                .collect::<Vec<_>>();
+
+            let skip = ((self.skip_until - start) * self.sample_rate as f64).round();
+            if skip > 0.0 {
+                let skip = (skip as usize).min(frames.len());
+                frames.drain(..skip);
+                start += skip as f64 / self.sample_rate as f64;
+            }
+            if frames.is_empty() {
+                continue;
+            }
+
+            return Ok(Some(Chunk {
+                generation: self.generation,
+                start,
+                frames,
+                end: false,
+                loop_back: self.looped.take(),
+            }));
+        }
+    }
+}
+
+/// Decode a whole sound up front, for analysis rather than playback.
+/// Returns the sample rate, the source's channels and the decoded frames, a packet at a time.
+/// Mono sources are decoded to the same signal on both sides.
+pub(crate) fn decode<P: AsRef<Path>>(
+    path: P,
+) -> Result<(u32, u16, impl Iterator<Item = Result<Vec<Frame>>>)> {
+    let mut decoding = Decoding::open(path.as_ref())?;
+    let (sample_rate, channels) = (decoding.sample_rate, decoding.channels);
+    let packets = std::iter::from_fn(move || {
+        decoding
+            .next_chunk()
+            .transpose()
+            .map(|chunk| chunk.map(|c| c.frames))
+    });
+    Ok((sample_rate, channels, packets))
+}
+
+/// What a voice got from its source
+enum Read {
+    Frame(Frame),
+    /// The decoder is lagging behind
+    Underrun,
+    End,
+}
+
+/// The playback side of a stream, living inside the mixer on the audio thread
+#[derive(Debug)]
+struct Voice {
+    shared: Arc<Shared>,
+    chunks: Receiver<Chunk>,
+    /// To jump back when looping
+    seeks: Sender<Seek>,
+    chunk: Option<Chunk>,
+    /// Read position inside the current chunk
+    index: usize,
+    /// Frames to play before reading on, left over when time stretching is switched off
+    pending: VecDeque<Frame>,
+    sample_rate: f64,
+    stretch: TimeStretch,
+    /// The two source frames the output is interpolated between
+    prev: Frame,
+    cur: Frame,
+    /// Fractional position between `prev` and `cur`
+    phase: f64,
+    /// Gain of the fade for transport actions, from 0 to 1
+    fade: f64,
+    silence: SilenceDetector,
+}
+
+impl Voice {
+    fn new(
+        shared: Arc<Shared>,
+        chunks: Receiver<Chunk>,
+        seeks: Sender<Seek>,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            shared,
+            chunks,
+            seeks,
+            chunk: None,
+            index: 0,
+            pending: VecDeque::new(),
+            sample_rate: sample_rate as f64,
+            stretch: TimeStretch::new(sample_rate),
+            prev: Frame::from_mono(0.0),
+            cur: Frame::from_mono(0.0),
+            phase: 1.0,
+            fade: 0.0,
+            silence: SilenceDetector::new(sample_rate),
+        }
+    }
+
+    /// Throw away audio decoded before the last seek and fetch the next chunk if needed.
+    fn refill(&mut self) {
+        let generation = self.shared.generation();
+        if let Some(chunk) = &self.chunk {
+            if chunk.generation != generation {
+                self.chunk = None;
+                self.index = 0;
+                self.pending.clear();
+                self.stretch.clear();
+                self.silence.reset();
+            }
+        }
+        while self.chunk.is_none() {
+            match self.chunks.try_recv() {
+                Ok(chunk) if chunk.generation == generation => self.chunk = Some(chunk),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// The next decoded frame
+    fn read(&mut self) -> Read {
+        if let Some(frame) = self.pending.pop_front() {
+            return Read::Frame(frame);
+        }
+        loop {
+            self.refill();
+            let chunk = match &self.chunk {
+                Some(chunk) => chunk,
+                None => return Read::Underrun,
+            };
+            if let Some(region) = chunk.loop_back {
+                if self.index == 0 && self.shared.loop_region() != Some(region) {
+                    // the loop changed since this was decoded, so go on from its old end
+                    self.shared.seek(&self.seeks, region.1);
+                    self.chunk = None;
+                    return Read::Underrun;
+                }
+            }
+            if chunk.end {
+                self.shared.set_position(chunk.start);
+                return Read::End;
+            }
+            if let Some(frame) = chunk.frames.get(self.index).copied() {
+                // what is audible lags behind what is fed to the stretcher
+                let index = self.index as f64 - self.stretch.buffered() as f64;
+                self.shared
+                    .set_position(chunk.start + index.max(0.0) / self.sample_rate);
+                self.index += 1;
+                if self.shared.skip_silence.load(Ordering::Relaxed) && !self.silence.keep(frame) {
+                    let skipped = load_f64(&self.shared.silence_skipped) + 1.0 / self.sample_rate;
+                    store_f64(&self.shared.silence_skipped, skipped);
+                    continue;
+                }
+                return Read::Frame(frame);
+            }
+            self.chunk = None;
+            self.index = 0;
+        }
+    }
+
+    /// The next frame at the source sample rate, time stretched if the pitch is preserved
+    fn pull(&mut self, speed: f64) -> Read {
+        if !self.shared.stretches(speed) {
+            if self.stretch.buffered() > 0 {
+                self.pending.extend(self.stretch.take_input());
+            }
+            return self.read();
+        }
+        loop {
+            if let Some(frame) = self.stretch.next(speed) {
+                return Read::Frame(frame);
+            }
+            match self.read() {
+                Read::Frame(frame) => self.stretch.push(frame),
+                other => return other,
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.shared.set_state(StreamState::Finished);
+        self.chunk = None;
+        // don't block the audio thread. If the handle is busy, the UI starts the follower.
+        if let Ok(mut follower) = self.shared.follower.try_lock() {
+            if let Some(next) = follower.take() {
+                if next.state() == StreamState::Paused {
+                    next.set_state(StreamState::Playing);
+                }
+            }
+        }
+    }
+
+    /// The next output frame, or `None` once this voice can be dropped.
+    fn next(&mut self, dt: f64) -> Option<Frame> {
+        let silence = Frame::from_mono(0.0);
+        // Nobody can resume this voice once all handles are gone
+        let orphaned = Arc::strong_count(&self.shared) == 1;
+        let playing = self.shared.state() == StreamState::Playing;
+        // keep playing while fading out
+        if !playing && self.fade <= 0.0 {
+            match self.shared.state() {
+                StreamState::Stopped => return None,
+                _ if orphaned => return None,
+                _ => {
+                    // keep draining stale chunks, so the decoder can follow seeks while paused
+                    self.refill();
+                    return Some(silence);
+                }
+            }
+        }
+
+        let pending_seek = self.shared.pending_seek();
+        if self.fade <= 0.0 {
+            if let Some(position) = pending_seek {
+                store_f64(&self.shared.pending_seek, f64::NAN);
+                self.shared.seek(&self.seeks, position);
+            } else if self.shared.position() == 0.0 {
+                // nothing to smooth over at the very start, so gapless playback stays gapless
+                self.fade = 1.0;
+            }
+        }
+        let target = if playing && pending_seek.is_none() {
+            1.0
+        } else {
+            0.0
+        };
+        let step = dt / load_f64(&self.shared.fade);
+        self.fade = if self.fade < target {
+            (self.fade + step).min(target)
+        } else {
+            (self.fade - step).max(target)
+        };
+
+        if let Some((start, end)) = self.shared.loop_region() {
+            if self.shared.position() >= end {
+                self.shared.seek(&self.seeks, start);
+            }
+        }
+
+        let speed = load_f64(&self.shared.speed);
+        // A stretched source already runs at the right speed, so only convert the sample rate
+        let step = if self.shared.stretches(speed) {
+            1.0
+        } else {
+            speed
+        };
+        while self.phase >= 1.0 {
+            match self.pull(speed) {
+                Read::Frame(frame) => {
+                    self.prev = self.cur;
+                    self.cur = frame;
+                    self.phase -= 1.0;
+                }
+                Read::Underrun => return Some(silence),
+                Read::End => {
+                    self.finish();
+                    self.fade = 0.0;
+                    return Some(silence);
+                }
+            }
+        }
+
+        // linear interpolation, as the output rate rarely matches the source
+        let frac = self.phase as f32;
+        let gain =
+            self.shared.next_gain(dt) * load_f64(&self.shared.replaygain) as f32 * self.fade as f32;
+        let (a, b) = (self.prev, self.cur);
+        self.phase += dt * self.sample_rate * step;
+        Some(Frame::new(
+            (a.left + (b.left - a.left) * frac) * gain,
+            (a.right + (b.right - a.right) * frac) * gain,
+        ))
+    }
+}
+
+/// Sums up all active voices. Added to the manager once and kept forever.
+#[derive(Debug)]
+struct MixerStream {
+    incoming: Receiver<Voice>,
+    voices: Vec<Voice>,
+}
+
+impl AudioStream for MixerStream {
+    fn next(&mut self, dt: f64) -> Frame {
+        self.voices.extend(self.incoming.try_iter());
+        let mut out = Frame::from_mono(0.0);
+        let mut i = 0;
+        while i < self.voices.len() {
+            match self.voices[i].next(dt) {
+                Some(frame) => {
+                    out.left += frame.left;
+                    out.right += frame.right;
+                    i += 1;
+                }
+                None => {
+                    self.voices.swap_remove(i);
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{offline_manager, play_for, tone_wav};
+
+    #[test]
+    fn plays_to_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut handle = manager
+            .load_stream(tone_wav(dir.path(), "a.wav", 1.0))
+            .unwrap();
+        assert!((handle.duration() - 1.0).abs() < 0.01);
+        assert_eq!(handle.state(), StreamState::Paused);
+
+        handle.resume();
+        play_for(&mut manager, 0.5);
+        assert_eq!(handle.state(), StreamState::Playing);
+        assert!(handle.position() > 0.3 && handle.position() < 0.6);
+
+        play_for(&mut manager, 1.0);
+        assert_eq!(handle.state(), StreamState::Finished);
+    }
+
+    #[test]
+    fn paused_stream_stays_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut handle = manager
+            .load_stream(tone_wav(dir.path(), "a.wav", 2.0))
+            .unwrap();
+        handle.resume();
+        play_for(&mut manager, 0.5);
+        handle.pause();
+        // let the fade out finish
+        play_for(&mut manager, 0.2);
+        let position = handle.position();
+        play_for(&mut manager, 1.0);
+        assert_eq!(handle.position(), position);
+        assert!(handle.paused_for().is_some());
+    }
+
+    #[test]
+    fn seeks_while_paused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut handle = manager
+            .load_stream(tone_wav(dir.path(), "a.wav", 3.0))
+            .unwrap();
+        handle.seek_to(2.0);
+        play_for(&mut manager, 0.1);
+        handle.resume();
+        play_for(&mut manager, 0.5);
+        assert!(handle.position() > 2.3 && handle.position() < 2.6);
+    }
+
+    #[test]
+    fn follower_starts_when_the_stream_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut first = manager
+            .load_stream(tone_wav(dir.path(), "a.wav", 0.5))
+            .unwrap();
+        let second = manager
+            .load_stream(tone_wav(dir.path(), "b.wav", 2.0))
+            .unwrap();
+        first.set_follower(Some(&second));
+        first.resume();
+        play_for(&mut manager, 1.0);
+        assert_eq!(first.state(), StreamState::Finished);
+        assert_eq!(second.state(), StreamState::Playing);
+        assert!(second.position() > 0.3 && second.position() < 0.6);
+    }
+
+    #[test]
+    fn loops_without_a_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut handle = manager
+            .load_stream(tone_wav(dir.path(), "a.wav", 3.0))
+            .unwrap();
+        handle.set_loop(Some((0.5, 1.0)));
+        handle.resume();
+        play_for(&mut manager, 0.1);
+
+        // a tone is never near silent for long, unless the stream ran dry. The equalizer
+        // rings on a little after that, so it is not quite silent either.
+        let mut longest_silence = 0;
+        let mut silence = 0;
+        for _ in 0..150 {
+            for frame in manager.record(0.01) {
+                silence = if frame.left.abs() < 1e-4 {
+                    silence + 1
+                } else {
+                    0
+                };
+                longest_silence = longest_silence.max(silence);
+            }
+            // at twice real time, so the decoder keeps up while other tests run too
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(longest_silence < 5, "silent for {} frames", longest_silence);
+        assert_eq!(handle.state(), StreamState::Playing);
+        assert!(handle.position() >= 0.5 && handle.position() < 1.0);
+    }
+
+    #[test]
+    fn plays_on_once_the_loop_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = offline_manager();
+        let mut handle = manager
+            .load_stream(tone_wav(dir.path(), "a.wav", 3.0))
+            .unwrap();
+        handle.set_loop(Some((0.5, 1.0)));
+        handle.resume();
+        play_for(&mut manager, 0.8);
+        // the jump back is decoded by now
+        handle.set_loop(None);
+        play_for(&mut manager, 0.5);
+        assert!(handle.position() > 1.1, "at {}", handle.position());
+    }
+
+    #[test]
+    fn plays_into_nothing_without_a_sound_card() {
+        let device = OutputKind::Device(None);
+        assert_eq!(device.fallback(&[]), Some(OutputKind::Null));
+        assert_eq!(device.fallback(&["Speakers".to_string()]), None);
+        assert_eq!(OutputKind::Offline.fallback(&[]), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager =
+            StreamManager::with_output(AudioManagerSettings::default(), OutputKind::Null).unwrap();
+        assert_eq!(manager.device_name(), Some("No sound card"));
+        let mut handle = manager
+            .load_stream(tone_wav(dir.path(), "a.wav", 2.0))
+            .unwrap();
+        handle.resume();
+        // time passes on its own, at the pace of a sound card
+        thread::sleep(Duration::from_millis(500));
+        assert!(handle.position() > 0.2 && handle.position() < 0.8);
+    }
+}
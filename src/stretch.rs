@@ -0,0 +1,224 @@
+//! Changing the speed of audio without changing its pitch, using WSOLA
+//! (waveform similarity overlap-add): Short windowed segments are taken from the input at
+//! the playback speed and overlapped at normal speed. Each segment is shifted slightly so its
+//! waveform lines up with the previous one, which avoids the phasing of plain overlap-add.
+
+use kira::Frame;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+#[derive(Debug)]
+pub struct TimeStretch {
+    /// Length of the overlapping segments, in frames
+    segment: usize,
+    /// How far a segment may be shifted to match the previous one
+    tolerance: usize,
+    window: Vec<f32>,
+    input: Vec<Frame>,
+    /// Where the next segment would ideally start in `input`
+    analysis: f64,
+    /// Start of the last segment in `input`
+    last: Option<usize>,
+    /// Overlap-add buffer. The first half is final once a segment has been added.
+    acc: Vec<Frame>,
+    output: VecDeque<Frame>,
+}
+
+impl TimeStretch {
+    pub fn new(sample_rate: u32) -> Self {
+        // 20ms segments sit between pitch periods of voices and the length of syllables
+        let segment = ((sample_rate / 50) as usize).max(16) & !1;
+        let window = (0..segment)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / segment as f32).cos())
+            .collect();
+        Self {
+            segment,
+            tolerance: (sample_rate / 160) as usize,
+            window,
+            input: vec![],
+            analysis: 0.0,
+            last: None,
+            acc: vec![Frame::from_mono(0.0); segment],
+            output: VecDeque::new(),
+        }
+    }
+
+    fn hop(&self) -> usize {
+        self.segment / 2
+    }
+
+    /// Feed one input frame
+    pub fn push(&mut self, frame: Frame) {
+        self.input.push(frame);
+    }
+
+    /// Number of input frames that have not been played yet
+    pub fn buffered(&self) -> usize {
+        self.input.len().saturating_sub(self.analysis as usize)
+    }
+
+    /// Take out the input that has not been played yet, to continue without stretching.
+    pub fn take_input(&mut self) -> Vec<Frame> {
+        let start = (self.analysis as usize).min(self.input.len());
+        let unplayed = self.input.split_off(start);
+        self.clear();
+        unplayed
+    }
+
+    /// Drop everything, e.g. after seeking
+    pub fn clear(&mut self) {
+        self.input.clear();
+        self.output.clear();
+        self.analysis = 0.0;
+        self.last = None;
+        self.acc.iter_mut().for_each(|f| *f = Frame::from_mono(0.0));
+    }
+
+    /// The next output frame, or `None` if more input needs to be pushed first.
+    pub fn next(&mut self, speed: f64) -> Option<Frame> {
+        if self.output.is_empty() {
+            self.add_segment(speed);
+        }
+        self.output.pop_front()
+    }
+
+    fn add_segment(&mut self, speed: f64) {
+        let hop = self.hop();
+        let center = self.analysis as usize;
+        let needed = match self.last {
+            Some(last) => (center + self.tolerance).max(last + hop) + self.segment,
+            None => center + self.segment,
+        };
+        if self.input.len() < needed {
+            return;
+        }
+
+        let start = match self.last {
+            Some(last) => self.best_match(center, last + hop),
+            None => center,
+        };
+
+        for i in 0..self.segment {
+            let w = self.window[i];
+            let f = self.input[start + i];
+            self.acc[i].left += f.left * w;
+            self.acc[i].right += f.right * w;
+        }
+        self.output.extend(self.acc.drain(..hop));
+        self.acc.resize(self.segment, Frame::from_mono(0.0));
+
+        self.last = Some(start);
+        self.analysis += hop as f64 * speed;
+
+        // forget input that no segment can reach anymore
+        let keep = start.min((self.analysis as usize).saturating_sub(self.tolerance));
+        if keep > 0 {
+            self.input.drain(..keep);
+            self.analysis -= keep as f64;
+            self.last = Some(start - keep);
+        }
+    }
+
+    /// The start near `center` whose waveform is most similar to what would naturally
+    /// follow the previous segment, starting at `natural`.
+    fn best_match(&self, center: usize, natural: usize) -> usize {
+        let hop = self.hop();
+        let mono = |i: usize| self.input[i].left + self.input[i].right;
+        let mut best = center;
+        let mut best_score = f32::MIN;
+        for candidate in center.saturating_sub(self.tolerance)..=center + self.tolerance {
+            let mut corr = 0.0;
+            let mut energy = 0.0;
+            // every other frame is plenty for a similarity measure
+            for i in (0..hop).step_by(2) {
+                let c = mono(candidate + i);
+                corr += c * mono(natural + i);
+                energy += c * c;
+            }
+            let score = corr / (energy.sqrt() + f32::EPSILON);
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn sine(freq: f32, secs: f32) -> Vec<Frame> {
+        (0..(secs * SAMPLE_RATE as f32) as usize)
+            .map(|i| Frame::from_mono((2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin()))
+            .collect()
+    }
+
+    fn stretch(input: &[Frame], speed: f64) -> Vec<Frame> {
+        let mut stretch = TimeStretch::new(SAMPLE_RATE);
+        let mut output = vec![];
+        for frame in input {
+            stretch.push(*frame);
+            while let Some(f) = stretch.next(speed) {
+                output.push(f);
+            }
+        }
+        output
+    }
+
+    /// Frequency estimated from rising zero crossings, skipping the fade in
+    fn frequency(frames: &[Frame]) -> f32 {
+        let frames = &frames[SAMPLE_RATE as usize / 10..];
+        let crossings = frames
+            .windows(2)
+            .filter(|w| w[0].left < 0.0 && w[1].left >= 0.0)
+            .count();
+        crossings as f32 / (frames.len() as f32 / SAMPLE_RATE as f32)
+    }
+
+    fn assert_stretched(speed: f64) {
+        let input = sine(440.0, 2.0);
+        let output = stretch(&input, speed);
+
+        let expected_len = input.len() as f64 / speed;
+        assert!(
+            (output.len() as f64 - expected_len).abs() < expected_len * 0.05,
+            "{} frames at {}x, expected about {}",
+            output.len(),
+            speed,
+            expected_len
+        );
+
+        let freq = frequency(&output);
+        assert!(
+            (freq - 440.0).abs() < 440.0 * 0.02,
+            "{} Hz at {}x",
+            freq,
+            speed
+        );
+    }
+
+    #[test]
+    fn keeps_pitch_when_faster() {
+        assert_stretched(1.5);
+    }
+
+    #[test]
+    fn keeps_pitch_when_slower() {
+        assert_stretched(0.75);
+    }
+
+    #[test]
+    fn unchanged_at_normal_speed() {
+        let input = sine(440.0, 0.5);
+        let output = stretch(&input, 1.0);
+        let hop = TimeStretch::new(SAMPLE_RATE).hop();
+        // past the fade in, segments overlap back to the original signal
+        for (i, (a, b)) in input.iter().zip(&output).enumerate().skip(hop) {
+            assert!((a.left - b.left).abs() < 1e-3, "frame {} differs", i);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Fixture audio and a manager that plays without a sound card, for the tests
+
+use crate::output::OutputKind;
+use crate::sound::MetaSound;
+use crate::stream::StreamManager;
+use kira::manager::AudioManagerSettings;
+use std::{
+    f32::consts::PI,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Write a stereo 440 Hz tone of `secs` seconds to `dir/name`
+pub fn tone_wav(dir: &Path, name: &str, secs: f64) -> PathBuf {
+    write_wav(dir, name, secs, 2, tone)
+}
+
+/// The same tone as `tone_wav`, on a single channel
+pub fn mono_tone_wav(dir: &Path, name: &str, secs: f64) -> PathBuf {
+    write_wav(dir, name, secs, 1, tone)
+}
+
+fn tone(t: f32) -> f32 {
+    0.5 * (2.0 * PI * 440.0 * t).sin()
+}
+
+/// Write `secs` seconds of digital silence to `dir/name`
+pub fn silent_wav(dir: &Path, name: &str, secs: f64) -> PathBuf {
+    write_wav(dir, name, secs, 2, |_| 0.0)
+}
+
+/// Write a WAV with the same signal, a function of time, on every channel
+fn write_wav(
+    dir: &Path,
+    name: &str,
+    secs: f64,
+    channels: u16,
+    signal: impl Fn(f32) -> f32,
+) -> PathBuf {
+    let path = dir.join(name);
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for i in 0..(secs * SAMPLE_RATE as f64) as usize {
+        let sample = signal(i as f32 / SAMPLE_RATE as f32);
+        let sample = (sample * i16::MAX as f32) as i16;
+        for _ in 0..channels {
+            writer.write_sample(sample).unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+    path
+}
+
+/// A playlist entry for a generated tone
+pub fn tone_sound(dir: &Path, name: &str, secs: f64) -> MetaSound {
+    MetaSound::default().with_path(tone_wav(dir, name, secs))
+}
+
+/// A manager that only plays as far as it is told to
+pub fn offline_manager() -> StreamManager {
+    StreamManager::with_output(AudioManagerSettings::default(), OutputKind::Offline).unwrap()
+}
+
+/// Play `secs` seconds of audio, a little at a time so the decoders keep up
+pub fn play_for(manager: &mut StreamManager, secs: f64) {
+    const STEP: f64 = 0.01;
+    for _ in 0..(secs / STEP).round() as usize {
+        manager.advance(STEP);
+        thread::sleep(Duration::from_millis(1));
+    }
+}
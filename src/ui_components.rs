@@ -1,23 +1,22 @@
-use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use eframe::egui::{
-    Color32, ComboBox, CtxRef, CursorIcon, Label, LayerId, Order, Response, SelectableLabel, Sense,
-    Stroke, Ui, Vec2,
+    Color32, ComboBox, CtxRef, CursorIcon, Label, LayerId, Order, Pos2, ProgressBar, Rect,
+    Response, SelectableLabel, Sense, Slider, Stroke, Ui, Vec2,
 };
-use kira::manager::AudioManager;
 
 use crate::{
-    sound::{MetaSound, SoundQueue},
+    dsp::{self, ChainSlot, ChannelSettings},
+    equalizer::{builtin_presets, EqualizerSettings, BANDS, MAX_GAIN},
+    loudness::{GainMode, LoudnessScanner},
+    output::output_devices,
+    player::{Command, Player},
+    sleep::SleepTimer,
+    stream::{FadePolicy, StreamManager},
     theme::{grad_button, Theme},
 };
 
-pub fn playlist_ui(
-    queue: &mut SoundQueue,
-    active_sound: &mut Option<MetaSound>,
-    play_count: &mut HashMap<MetaSound, usize>,
-    manager: &mut AudioManager,
-    ui: &mut Ui,
-) {
+pub fn playlist_ui(player: &mut Player, manager: &mut StreamManager, ui: &mut Ui) {
     ui.collapsing("♫ Playlist", |ui| {
         ui.vertical_centered_justified(|ui| {
             let mut drag_index: Option<usize> = None;
@@ -25,10 +24,10 @@ pub fn playlist_ui(
             // if ui.button("clr").clicked() {
             //     queue.clear();
             // }
-            for (i, sound) in queue.clone().iter().enumerate() {
+            for (i, sound) in player.queue().clone().iter().enumerate() {
                 ui.horizontal(|ui| {
                     let pl_item = ui
-                        .selectable_label(Some(sound) == active_sound.as_ref(), &sound.name)
+                        .selectable_label(Some(sound) == player.active_sound(), &sound.name)
                         .interact(Sense::click_and_drag());
 
                     if pl_item.drag_released() {
@@ -36,7 +35,7 @@ pub fn playlist_ui(
                     }
 
                     if pl_item.double_clicked() {
-                        play_as_active(active_sound, sound, manager, play_count);
+                        player.command(Command::PlaySound(sound.clone()), manager);
                     }
 
                     if pl_item.dragged() {
@@ -80,7 +79,7 @@ pub fn playlist_ui(
                             .add(Label::new("🗙").small().weak().sense(Sense::click()))
                             .clicked()
                         {
-                            queue.remove(i);
+                            player.command(Command::Remove(i), manager);
                         }
                     }
 
@@ -92,25 +91,19 @@ pub fn playlist_ui(
 
             if ui.input().pointer.any_released() {
                 // swap
-                if let (Some(drag), Some(drop)) = (drag_index, drop_index) {
-                    let elem = queue.remove(drag);
-                    queue.insert(drop, elem);
+                if let (Some(from), Some(to)) = (drag_index, drop_index) {
+                    player.command(Command::Move { from, to }, manager);
                 }
             }
         });
     });
 }
 
-pub fn playcount_ui(
-    // queue_index: &mut usize,
-    active_sound: &mut Option<MetaSound>,
-    counter: &mut HashMap<MetaSound, usize>,
-    manager: &mut AudioManager,
-    ui: &mut Ui,
-) {
+pub fn playcount_ui(player: &mut Player, manager: &mut StreamManager, ui: &mut Ui) {
     ui.collapsing("🔥 Most played", |ui| {
         // for s in counter
-        let mut sorted = counter
+        let mut sorted = player
+            .play_count()
             .iter()
             .map(|x| (x.0.clone(), *x.1))
             .collect::<Vec<_>>();
@@ -121,7 +114,7 @@ pub fn playcount_ui(
             ui.horizontal(|ui| {
                 ui.label(format!("{:02}", sound.1));
                 if grad_button("▶", ui).clicked() {
-                    play_as_active(active_sound, &sound.0, manager, counter);
+                    player.command(Command::PlaySound(sound.0.clone()), manager);
                 }
                 ui.label(&sound.0.name);
             });
@@ -129,63 +122,45 @@ pub fn playcount_ui(
     });
 }
 
-pub fn favourite_ui(
-    // queue_index: &mut usize,
-    active_sound: &mut Option<MetaSound>,
-    favourites: &mut HashSet<MetaSound>,
-    counter: &mut HashMap<MetaSound, usize>,
-    manager: &mut AudioManager,
-    ui: &mut Ui,
-) {
+pub fn favourite_ui(player: &mut Player, manager: &mut StreamManager, ui: &mut Ui) {
     ui.collapsing("♡ Favourites", |ui| {
-        for favsound in favourites.iter() {
+        let mut play = None;
+        for favsound in player.favourites().iter() {
             ui.horizontal(|ui| {
                 if grad_button("▶", ui).clicked() {
-                    play_as_active(active_sound, favsound, manager, counter);
+                    play = Some(favsound.clone());
                 }
                 ui.label(&favsound.name);
             });
         }
+        if let Some(sound) = play {
+            player.command(Command::PlaySound(sound), manager);
+        }
     });
 }
 
-pub fn bookmark_ui(
-    // queue_index: &mut usize,
-    active_sound: &mut Option<MetaSound>,
-    bookmarks: &mut HashSet<MetaSound>,
-    manager: &mut AudioManager,
-    ui: &mut Ui,
-) {
+pub fn bookmark_ui(player: &mut Player, manager: &mut StreamManager, ui: &mut Ui) {
     ui.collapsing("🔖 Bookmarks", |ui| {
-        for s in bookmarks.iter() {
+        let mut jump = None;
+        for s in player.bookmarks().iter() {
             ui.label(&s.name);
             ui.horizontal(|ui| {
                 for b in &s.bookmarks {
                     if grad_button(format!("{:.1}", b), ui).clicked() {
-                        if let Some(active) = active_sound {
-                            //check if current sound is the one referenced in bookmark
-                            if active == s {
-                                if let Some(instancehandle) = active.instancehandle.as_mut() {
-                                    let _ = instancehandle.seek_to(*b);
-                                }
-                            } else {
-                                active.stop();
-                                *active = s.clone();
-                                let _ = active.play_load_mut(manager);
-                                if let Some(instancehandle) = active.instancehandle.as_mut() {
-                                    let _ = instancehandle.seek_to(*b);
-                                }
-                            }
-                        }
+                        jump = Some(Command::PlayBookmark(s.clone(), *b));
                     }
                 }
             });
         }
+        if let Some(command) = jump {
+            player.command(command, manager);
+        }
     });
 }
 
-/// The scrollbar / scrub bar
-pub fn scrubber(ui: &mut Ui, scale: f32) -> Response {
+/// The scrollbar / scrub bar. `loop_marks` are the A and B points of a loop region,
+/// as fractions of the length.
+pub fn scrubber(ui: &mut Ui, scale: f32, loop_marks: (Option<f32>, Option<f32>)) -> Response {
     let mut dim = ui.available_rect_before_wrap();
     dim.set_height(ui.spacing().interact_size.y);
     let x = ui.allocate_rect(dim, Sense::click());
@@ -196,6 +171,7 @@ pub fn scrubber(ui: &mut Ui, scale: f32) -> Response {
         ui.style().visuals.extreme_bg_color,
         Stroke::default(),
     );
+    let full = dim;
     dim.set_width(dim.width() * scale);
     ui.painter().rect(
         dim,
@@ -203,12 +179,121 @@ pub fn scrubber(ui: &mut Ui, scale: f32) -> Response {
         ui.style().visuals.widgets.active.bg_fill,
         Stroke::default(),
     );
+
+    let stroke = ui.style().visuals.selection.stroke;
+    let at = |fraction: f32| full.left() + full.width() * fraction;
+    match loop_marks {
+        (Some(a), Some(b)) => {
+            let region = Rect::from_x_y_ranges(at(a)..=at(b), full.y_range());
+            ui.painter().rect(
+                region,
+                radius,
+                ui.style().visuals.selection.bg_fill.linear_multiply(0.4),
+                stroke,
+            );
+        }
+        (Some(a), None) => {
+            ui.painter().line_segment(
+                [
+                    Pos2::new(at(a), full.top()),
+                    Pos2::new(at(a), full.bottom()),
+                ],
+                stroke,
+            );
+        }
+        _ => {}
+    }
     x
 }
 
-pub fn settings_ui(theme: &mut Theme, powersave: &mut bool, ui: &mut Ui) {
+/// Application settings edited in `settings_ui`
+pub struct Settings<'a> {
+    pub theme: &'a mut Theme,
+    pub powersave: &'a mut bool,
+    pub preserve_pitch: &'a mut bool,
+    pub fades: &'a mut FadePolicy,
+    pub replaygain: &'a mut GainMode,
+    /// Output device, `None` for the system default
+    pub device: &'a mut Option<String>,
+    /// Output devices to choose from
+    pub devices: &'a mut Vec<String>,
+}
+
+pub fn settings_ui(
+    settings: Settings,
+    player: &mut Player,
+    manager: &mut StreamManager,
+    ui: &mut Ui,
+) {
+    let Settings {
+        theme,
+        powersave,
+        preserve_pitch,
+        fades,
+        replaygain,
+        device,
+        devices,
+    } = settings;
     ui.collapsing("⛭ Settings", |ui| {
         ui.checkbox(powersave, "Powersave mode");
+        let mut crossfade = player.crossfade();
+        if ui
+            .add(Slider::new(&mut crossfade, 0.0..=12.0).text("Crossfade (s)"))
+            .changed()
+        {
+            player.command(Command::SetCrossfade(crossfade), manager);
+        }
+        ui.checkbox(preserve_pitch, "Keep pitch when changing speed");
+        let mut resume = player.resume_settings();
+        let mut minutes = resume.min_duration / 60.0;
+        if ui
+            .add(Slider::new(&mut minutes, 0.0..=60.0).text("Resume sounds longer than (min)"))
+            .changed()
+        {
+            resume.min_duration = minutes * 60.0;
+        }
+        let mut minutes = resume.rewind_after / 60.0;
+        if ui
+            .add(Slider::new(&mut minutes, 0.0..=60.0).text("Rewind after a pause of (min)"))
+            .changed()
+        {
+            resume.rewind_after = minutes * 60.0;
+        }
+        ui.add(Slider::new(&mut resume.rewind, 0.0..=60.0).text("Rewind by (s)"));
+        if resume != player.resume_settings() {
+            player.command(Command::SetResume(resume), manager);
+        }
+        ui.add(Slider::new(&mut fades.pause, 0.0..=2.0).text("Pause fade (s)"));
+        ui.add(Slider::new(&mut fades.resume, 0.0..=2.0).text("Resume fade (s)"));
+        ui.add(Slider::new(&mut fades.stop, 0.0..=2.0).text("Stop fade (s)"));
+        ui.add(Slider::new(&mut fades.seek, 0.0..=0.5).text("Seek fade (s)"));
+
+        ComboBox::from_label("ReplayGain")
+            .selected_text(format!("{:?}", replaygain))
+            .show_ui(ui, |ui| {
+                for mode in [
+                    GainMode::Off,
+                    GainMode::Track,
+                    GainMode::Album,
+                    GainMode::Auto,
+                ] {
+                    ui.selectable_value(replaygain, mode, format!("{:?}", mode));
+                }
+            });
+
+        ui.horizontal(|ui| {
+            ComboBox::from_label("Output device")
+                .selected_text(device.as_deref().unwrap_or("System default"))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(device, None, "System default");
+                    for name in devices.iter() {
+                        ui.selectable_value(device, Some(name.clone()), name);
+                    }
+                });
+            if ui.button("⟳").on_hover_text("Look for devices").clicked() {
+                *devices = output_devices();
+            }
+        });
 
         ComboBox::from_label("Theme")
             .selected_text(format!("{:?}", theme))
@@ -225,14 +310,185 @@ pub fn settings_ui(theme: &mut Theme, powersave: &mut bool, ui: &mut Ui) {
     });
 }
 
-pub fn play_as_active(
-    active_sound: &mut Option<MetaSound>,
-    sound: &MetaSound,
-    manager: &mut AudioManager,
-    counter: &mut HashMap<MetaSound, usize>,
-) {
-    let _ = active_sound.as_mut().map(|s| s.stop());
-    *active_sound = Some(sound.clone());
-    let _ = active_sound.as_mut().map(|s| s.play_load_mut(manager));
-    *counter.entry(sound.clone()).or_insert(0) += 1;
+/// Pick a sleep timer, showing the time left while one runs
+pub fn sleep_ui(player: &mut Player, manager: &mut StreamManager, ui: &mut Ui) {
+    let remaining = player
+        .sleep_timer()
+        .zip(player.active_sound())
+        .map(|(timer, sound)| timer.remaining(sound) as u64);
+    let text = match remaining {
+        Some(secs) => format!("💤 {}:{:02}", secs / 60, secs % 60),
+        None => "💤".to_string(),
+    };
+
+    let mut choice = None;
+    ComboBox::from_id_source("sleep")
+        .selected_text(text)
+        .show_ui(ui, |ui| {
+            if ui
+                .selectable_label(player.sleep_timer().is_none(), "Off")
+                .clicked()
+            {
+                choice = Some(None);
+            }
+            for minutes in [15, 30, 60] {
+                if ui
+                    .selectable_label(false, format!("{} min", minutes))
+                    .clicked()
+                {
+                    choice = Some(Some(SleepTimer::after(Duration::from_secs(minutes * 60))));
+                }
+            }
+            if let Some(sound) = player.active_sound() {
+                if ui.selectable_label(false, "End of track").clicked() {
+                    choice = SleepTimer::end_of_track(sound).map(Some);
+                }
+                if ui.selectable_label(false, "End of chapter").clicked() {
+                    choice = SleepTimer::end_of_chapter(sound).map(Some);
+                }
+            }
+        });
+
+    if let Some(timer) = choice {
+        player.command(Command::SetSleepTimer(timer), manager);
+    }
+}
+
+pub fn equalizer_ui(equalizer: &mut EqualizerSettings, ui: &mut Ui) {
+    ui.collapsing("🎚 Equalizer", |ui| {
+        ui.checkbox(&mut equalizer.enabled, "Enabled");
+
+        let builtin = builtin_presets();
+        let current = builtin
+            .iter()
+            .chain(&equalizer.presets)
+            .find(|p| p.gains == equalizer.gains)
+            .map(|p| p.name.clone());
+        let mut chosen = None;
+        ComboBox::from_label("Preset")
+            .selected_text(current.clone().unwrap_or_else(|| "Custom".to_string()))
+            .show_ui(ui, |ui| {
+                for preset in builtin.iter().chain(&equalizer.presets) {
+                    if ui
+                        .selectable_label(Some(&preset.name) == current.as_ref(), &preset.name)
+                        .clicked()
+                    {
+                        chosen = Some(preset.gains);
+                    }
+                }
+            });
+        if let Some(gains) = chosen {
+            equalizer.gains = gains;
+            equalizer.enabled = true;
+        }
+
+        ui.horizontal(|ui| {
+            for (gain, freq) in equalizer.gains.iter_mut().zip(&BANDS) {
+                ui.vertical(|ui| {
+                    ui.add(
+                        Slider::new(gain, -MAX_GAIN..=MAX_GAIN)
+                            .vertical()
+                            .show_value(false),
+                    );
+                    if *freq >= 1000.0 {
+                        ui.label(format!("{:.0}k", freq / 1000.0));
+                    } else {
+                        ui.label(format!("{:.0}", freq));
+                    }
+                });
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut equalizer.preset_name);
+            let name = equalizer.preset_name.trim().to_string();
+            if ui.button("💾 Save preset").clicked() && !name.is_empty() {
+                equalizer.save_preset(&name);
+                equalizer.preset_name.clear();
+            }
+            // only presets saved by the user can go
+            if let Some(name) = current.filter(|c| equalizer.presets.iter().any(|p| &p.name == c)) {
+                if ui.button(format!("🗑 {}", name)).clicked() {
+                    equalizer.delete_preset(&name);
+                }
+            }
+        });
+    });
+}
+
+pub fn effects_ui(effects: &mut Vec<ChainSlot>, ui: &mut Ui) {
+    ui.collapsing("✨ Effects", |ui| {
+        let len = effects.len();
+        // index of an effect to swap with the one before it
+        let mut swap = None;
+        let mut remove = None;
+        for (i, slot) in effects.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut slot.enabled, slot.effect.name());
+                if i > 0 && ui.button("⬆").clicked() {
+                    swap = Some(i);
+                }
+                if i + 1 < len && ui.button("⬇").clicked() {
+                    swap = Some(i + 1);
+                }
+                if ui.button("🗙").clicked() {
+                    remove = Some(i);
+                }
+            });
+            for parameter in slot.effect.parameters() {
+                ui.add(Slider::new(parameter.value, parameter.range).text(parameter.name));
+            }
+        }
+        if let Some(i) = swap {
+            effects.swap(i - 1, i);
+        }
+        if let Some(i) = remove {
+            effects.remove(i);
+        }
+
+        ComboBox::from_label("Add effect")
+            .selected_text("")
+            .show_ui(ui, |ui| {
+                for effect in dsp::available() {
+                    if ui.selectable_label(false, effect.name()).clicked() {
+                        effects.push(ChainSlot::new(effect));
+                    }
+                }
+            });
+    });
+}
+
+pub fn channels_ui(channels: &mut ChannelSettings, ui: &mut Ui) {
+    ui.collapsing("🎧 Channels", |ui| {
+        ui.horizontal(|ui| {
+            ui.add(Slider::new(&mut channels.balance, -1.0..=1.0).text("Balance"));
+            if ui.button("Center").clicked() {
+                channels.balance = 0.0;
+            }
+        });
+        ui.checkbox(&mut channels.mono, "Mono, for a single earbud");
+        ui.checkbox(&mut channels.swap, "Swap left and right");
+    });
+}
+
+/// Show why nothing can be heard, with a way to try again right away
+pub fn output_error_ui(manager: &mut StreamManager, ui: &mut Ui) {
+    if let Some(error) = manager.output_error().map(|e| e.to_string()) {
+        ui.horizontal(|ui| {
+            ui.colored_label(Color32::from_rgb(230, 90, 70), format!("⚠ {}", error));
+            if ui.button("Retry").clicked() {
+                let _ = manager.reconnect();
+            }
+        });
+    }
+}
+
+/// Show how far the loudness analysis got, while it runs
+pub fn scan_progress_ui(scanner: &Option<LoudnessScanner>, ui: &mut Ui) {
+    if let Some((done, queued)) = scanner.as_ref().and_then(|s| s.progress()) {
+        ui.add(
+            ProgressBar::new(done as f32 / queued as f32)
+                .text(format!("Measuring loudness {}/{}", done, queued)),
+        );
+    }
 }